@@ -1,23 +1,352 @@
 use nickel::{Nickel, HttpRouter, MediaType};
 use nickel::Mountable;
-use serde_json::value::ToJson;
+use nickel::status::StatusCode;
+use serde_json;
+use serde_json::Value;
+use serde_json::builder::{ArrayBuilder, ObjectBuilder};
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+use ::flota::hash;
 use ::flota::config::Config;
+use ::flota::config::cluster::watchpoint::WatchPoint;
+use ::flota::runlog::RunLogs;
+use ::flota::spool::Spool;
+use ::flota::store;
+use ::flota::store::{ClusterRecord, History, RunState, Store};
 use ::util::errors::*;
+use ::virt::conn::Conn;
+use ::virt::domain::Domain;
+
+mod stream;
+use self::stream::{ConsoleStream, ExecOutputStream, StreamSlots};
+
+// handlers either build a JSON value or fail with a status to answer.
+type Reply = ::std::result::Result<Value, (StatusCode, String)>;
+
+fn respond(reply: Reply) -> (StatusCode, String) {
+    match reply {
+        // the API's "()" is an empty 204.
+        Ok(Value::Null) => (StatusCode::NoContent, String::new()),
+        Ok(val) => (StatusCode::Ok, serde_json::to_string(&val).unwrap()),
+        Err((status, msg)) => {
+            let body = ObjectBuilder::new().insert("error", msg).build();
+            (status, serde_json::to_string(&body).unwrap())
+        }
+    }
+}
+
+fn internal(e: Error) -> (StatusCode, String) {
+    error!("{}", e);
+    (StatusCode::InternalServerError, e.to_string())
+}
+
+fn parse_id(id: Option<&str>) -> ::std::result::Result<u64, (StatusCode, String)> {
+    id.and_then(|id| id.parse().ok())
+      .ok_or((StatusCode::BadRequest, format!("invalid id: {}", id.unwrap_or(""))))
+}
+
+fn find_cluster(store: &Store, id: Option<&str>)
+                -> ::std::result::Result<ClusterRecord, (StatusCode, String)> {
+    let id = try!(parse_id(id));
+    try!(store.clusters().map_err(internal))
+        .into_iter()
+        .find(|c| c.id == id)
+        .ok_or((StatusCode::NotFound, format!("no such cluster: {}", id)))
+}
+
+fn history_to_json(history: &History) -> Value {
+    ObjectBuilder::new()
+        .insert("id", history.id)
+        .insert("config_id", history.config_id)
+        .insert("results", &history.results)
+        .insert("passed", history.passed)
+        .build()
+}
+
+fn ids_to_json<I: Iterator<Item = u64>>(ids: I) -> Value {
+    ids.fold(ArrayBuilder::new(), |builder, id| {
+        builder.push_object(|obj| obj.insert("id", id))
+    }).build()
+}
+
+fn configs(store: &Store) -> Reply {
+    let clusters = try!(store.clusters().map_err(internal));
+    Ok(clusters.iter().fold(ArrayBuilder::new(), |builder, c| {
+        builder.push_object(|obj| {
+            obj.insert("id", c.config_id)
+               .insert("cluster_id", c.id)
+               .insert("name", &c.name)
+        })
+    }).build())
+}
+
+fn config(store: &Store, id: Option<&str>) -> Reply {
+    let id = try!(parse_id(id));
+    try!(store.clusters().map_err(internal))
+        .into_iter()
+        .find(|c| c.config_id == id)
+        .map(|c| serde_json::to_value(&c))
+        .ok_or((StatusCode::NotFound, format!("no such config: {}", id)))
+}
+
+fn clusters(store: &Store) -> Reply {
+    let clusters = try!(store.clusters().map_err(internal));
+    Ok(clusters.iter().fold(ArrayBuilder::new(), |builder, c| {
+        builder.push_object(|obj| obj.insert("id", c.id).insert("name", &c.name))
+    }).build())
+}
+
+fn cluster(store: &Store, id: Option<&str>) -> Reply {
+    let cluster = try!(find_cluster(store, id));
+    let histories = try!(store.histories(cluster.id).map_err(internal));
+    Ok(ObjectBuilder::new()
+        .insert("id", cluster.id)
+        .insert("name", &cluster.name)
+        .insert("watchpoints", cluster.watchpoints.iter().map(|w| w.id).collect::<Vec<u64>>())
+        .insert("histories", histories.iter().map(|h| h.id).collect::<Vec<u64>>())
+        .build())
+}
+
+// Run logs of the histories go along with them.
+fn delete_run_logs(store: &Store, logs: &RunLogs, cluster_id: u64) -> Result<()> {
+    for history in try!(store.histories(cluster_id)).iter() {
+        try!(logs.delete(history.id));
+    }
+    Ok(())
+}
+
+fn delete_cluster(store: &Store, logs: &RunLogs, id: Option<&str>) -> Reply {
+    let cluster = try!(find_cluster(store, id));
+    try!(delete_run_logs(store, logs, cluster.id).map_err(internal));
+    try!(store.delete_cluster(cluster.id).map_err(internal));
+    Ok(Value::Null)
+}
+
+fn watchpoints(store: &Store, id: Option<&str>) -> Reply {
+    let cluster = try!(find_cluster(store, id));
+    Ok(ids_to_json(cluster.watchpoints.iter().map(|w| w.id)))
+}
+
+fn add_watchpoint(store: &Store, spool: &Spool, id: Option<&str>, body: &str) -> Reply {
+    let cluster = try!(find_cluster(store, id));
+    let bad_request = |msg: String| (StatusCode::BadRequest, msg);
+    let body: Value = try!(serde_json::from_str(body)
+                               .map_err(|e| bad_request(format!("invalid body: {}", e))));
+    let watchpoint: WatchPoint = match body.find("ident") {
+        Some(ident) => {
+            try!(serde_json::from_value(ident.clone())
+                     .map_err(|e| bad_request(format!("invalid ident: {}", e))))
+        },
+        None => return Err(bad_request("`ident` must be specified".to_string())),
+    };
+    match body.find("type").and_then(|t| t.as_str()) {
+        Some(kind) if kind != watchpoint.kind() => {
+            return Err(bad_request(format!("ident is not of type {}", kind)));
+        },
+        Some(_) => {},
+        None => return Err(bad_request("`type` must be specified".to_string())),
+    }
+    // commands would run on our side as whoever flota runs as.
+    if let WatchPoint::Command { .. } = watchpoint {
+        return Err((StatusCode::Forbidden,
+                    "command watchpoints are only accepted in the config file".to_string()));
+    }
+    if cluster.watchpoints.iter().any(|w| w.watchpoint == watchpoint) {
+        return Err((StatusCode::Conflict, "already watched".to_string()));
+    }
+    try!(store.add_watchpoint(cluster.id, &watchpoint).map_err(internal));
+    // watched from the next cycle of the main loop on.
+    try!(spool.request_reload().map_err(internal));
+    Ok(Value::Null)
+}
+
+fn watchpoint(store: &Store, id: Option<&str>, watchpoint_id: Option<&str>) -> Reply {
+    let cluster = try!(find_cluster(store, id));
+    let watchpoint_id = try!(parse_id(watchpoint_id));
+    let record = try!(cluster.watchpoints
+        .into_iter()
+        .find(|w| w.id == watchpoint_id)
+        .ok_or((StatusCode::NotFound, format!("no such watchpoint: {}", watchpoint_id))));
+    let perceptions = try!(store.perceptions(&record.watchpoint).map_err(internal));
+    Ok(ObjectBuilder::new()
+        .insert("id", record.id)
+        .insert("type", record.watchpoint.kind())
+        .insert("ident", &record.watchpoint)
+        .insert("histories", perceptions.iter().map(|p| hash(&p.value)).collect::<Vec<u64>>())
+        .build())
+}
+
+fn histories(store: &Store, id: Option<&str>) -> Reply {
+    let cluster = try!(find_cluster(store, id));
+    let histories = try!(store.histories(cluster.id).map_err(internal));
+    Ok(ids_to_json(histories.iter().map(|h| h.id)))
+}
+
+fn delete_histories(store: &Store, logs: &RunLogs, id: Option<&str>) -> Reply {
+    let cluster = try!(find_cluster(store, id));
+    try!(delete_run_logs(store, logs, cluster.id).map_err(internal));
+    try!(store.delete_histories(cluster.id).map_err(internal));
+    Ok(Value::Null)
+}
+
+fn history(store: &Store, id: Option<&str>, history_id: Option<&str>) -> Reply {
+    let cluster = try!(find_cluster(store, id));
+    let history_id = try!(parse_id(history_id));
+    try!(store.histories(cluster.id).map_err(internal))
+        .iter()
+        .find(|h| h.id == history_id)
+        .map(history_to_json)
+        .ok_or((StatusCode::NotFound, format!("no such history: {}", history_id)))
+}
+
+fn find_run(store: &Store, id: Option<&str>, run_id: Option<&str>)
+            -> ::std::result::Result<History, (StatusCode, String)> {
+    let cluster = try!(find_cluster(store, id));
+    let run_id = try!(parse_id(run_id));
+    try!(store.histories(cluster.id).map_err(internal))
+        .into_iter()
+        .find(|h| h.id == run_id)
+        .ok_or((StatusCode::NotFound, format!("no such run: {}", run_id)))
+}
+
+fn run_to_json(history: &History) -> Value {
+    ObjectBuilder::new()
+        .insert("id", history.id)
+        .insert("state", history.state)
+        .insert("causes", &history.causes)
+        .insert("results", &history.results)
+        .insert("passed", history.passed)
+        .build()
+}
+
+fn request_run(store: &Store, spool: &Spool, id: Option<&str>, body: &str, origin: &str)
+               -> Reply {
+    let cluster = try!(find_cluster(store, id));
+    // who asked for it, if told. otherwise the client's address.
+    let requested_by = serde_json::from_str::<Value>(body).ok()
+        .and_then(|v| v.find("requested_by").and_then(|r| r.as_str()).map(|r| r.to_string()))
+        .unwrap_or(origin.to_string());
+    let history = History::requested(&cluster, &requested_by);
+    // saved first so that the run can be inspected right away.
+    try!(store.save_history(&history).map_err(internal));
+    try!(spool.request_run(&history).map_err(internal));
+    Ok(run_to_json(&history))
+}
+
+fn run_status(store: &Store, id: Option<&str>, run_id: Option<&str>) -> Reply {
+    find_run(store, id, run_id).map(|h| run_to_json(&h))
+}
+
+fn cancel_run(store: &Store, spool: &Spool, id: Option<&str>, run_id: Option<&str>) -> Reply {
+    let history = try!(find_run(store, id, run_id));
+    match history.state {
+        RunState::Pending | RunState::Running => {
+            // the main loop stops it at the next exec and tears its
+            // hosts down.
+            try!(spool.request_cancel(history.id).map_err(internal));
+            Ok(Value::Null)
+        },
+        _ => Err((StatusCode::Conflict, format!("run {} is not in progress", history.id))),
+    }
+}
+
+fn hosts(store: &Store, id: Option<&str>) -> Reply {
+    let cluster = try!(find_cluster(store, id));
+    Ok(ids_to_json(cluster.hosts.iter().map(|h| h.id)))
+}
+
+// runs of the cluster in which the host took part, each narrowed down
+// to the host's own results.
+fn host_histories_of(store: &Store, cluster: &ClusterRecord, hostname: &str)
+                     -> ::std::result::Result<Vec<History>, (StatusCode, String)> {
+    let histories = try!(store.histories(cluster.id).map_err(internal));
+    Ok(histories.into_iter().filter_map(|mut h| {
+        h.results.retain(|r| r.host == hostname);
+        if h.results.is_empty() {
+            return None;
+        }
+        h.passed = h.results.iter().all(|r| r.passed);
+        Some(h)
+    }).collect())
+}
+
+fn host(store: &Store, hypervisor: &str, id: Option<&str>, host_id: Option<&str>) -> Reply {
+    let cluster = try!(find_cluster(store, id));
+    let host_id = try!(parse_id(host_id));
+    let record = try!(cluster.hosts
+        .iter()
+        .find(|h| h.id == host_id)
+        .cloned()
+        .ok_or((StatusCode::NotFound, format!("no such host: {}", host_id))));
+    let state = match Conn::open(hypervisor) {
+        Ok(conn) => {
+            match Domain::find(&record.hostname, &conn) {
+                Some(domain) => {
+                    domain.state()
+                          .map(|s| serde_json::to_value(&s))
+                          .unwrap_or(Value::String("Unknown".to_string()))
+                },
+                None => Value::String("Undefined".to_string()),
+            }
+        },
+        Err(e) => {
+            warn!("{}", e);
+            Value::String("Unknown".to_string())
+        }
+    };
+    let histories = try!(host_histories_of(store, &cluster, &record.hostname));
+    Ok(ObjectBuilder::new()
+        .insert("id", record.id)
+        .insert("name", &record.hostname)
+        .insert("state", state)
+        .insert("histories", histories.iter().map(|h| h.id).collect::<Vec<u64>>())
+        .build())
+}
+
+fn host_histories(store: &Store, id: Option<&str>, host_id: Option<&str>) -> Reply {
+    let cluster = try!(find_cluster(store, id));
+    let host_id = try!(parse_id(host_id));
+    let hostname = try!(cluster.hosts
+        .iter()
+        .find(|h| h.id == host_id)
+        .map(|h| h.hostname.clone())
+        .ok_or((StatusCode::NotFound, format!("no such host: {}", host_id))));
+    let histories = try!(host_histories_of(store, &cluster, &hostname));
+    Ok(histories.iter().fold(ArrayBuilder::new(), |builder, h| {
+        builder.push(history_to_json(h))
+    }).build())
+}
+
+pub fn run(config_path: &Path) -> Result<i32> {
+    let config = try!(Config::from_toml_file(config_path));
+    let store: Arc<Store> = try!(store::open(&config.setting));
+    let spool = try!(Spool::open());
+    let logs = try!(RunLogs::open());
+    let hypervisor = config.setting.hypervisor.clone();
+    let slots = StreamSlots::default();
 
-pub fn run() -> Result<i32> {
     let mut server = Nickel::new();
     let mut router = Nickel::router();
 
     // [GET] /configs
+    //
+    // returns:
+    // [{"id":NUM, "cluster_id":NUM, "name":STRING},...]
+    let s = store.clone();
     router.get("/configs", middleware! {|_, mut res|
         res.set(MediaType::Json);
-        //Config::get_all().unwrap().to_json().as_str().unwrap()
-        unimplemented!()
+        respond(configs(&*s))
     });
     // [GET] /configs/:id
-    server.get("/configs/:id", middleware! {|_, mut res|
+    //
+    // returns:
+    // {"id":NUM, "name":STRING, "config_id":NUM,
+    //  "watchpoints":ARRAY(STRUCT), "hosts":ARRAY(STRUCT)}
+    let s = store.clone();
+    router.get("/configs/:id", middleware! {|req, mut res|
         res.set(MediaType::Json);
-        unimplemented!()
+        respond(config(&*s, req.param("id")))
     });
     // [GET] /clusters
     //
@@ -25,65 +354,176 @@ pub fn run() -> Result<i32> {
     // [{"id":NUM, "name":STRING},
     //  {"id":NUM, "name":STRING},...]
     // -------------------------------
+    let s = store.clone();
     router.get("/clusters", middleware! {|_, mut res|
-        unimplemented!()
+        res.set(MediaType::Json);
+        respond(clusters(&*s))
     });
     // [GET] /clusters/:id
     //
     // returns:
     // {"id":NUM, "name":STRING, "watchpoints":ARRAY(NUM)}, "histories":ARRAY(NUM)}
-
+    let s = store.clone();
+    router.get("/clusters/:id", middleware! {|req, mut res|
+        res.set(MediaType::Json);
+        respond(cluster(&*s, req.param("id")))
+    });
     // [DELETE] /clusters/:id
     //
     // returns:
     // ()
-
+    let s = store.clone();
+    let l = logs.clone();
+    router.delete("/clusters/:id", middleware! {|req, mut res|
+        res.set(MediaType::Json);
+        respond(delete_cluster(&*s, &l, req.param("id")))
+    });
     // [GET] /clusters/:id/watchpoints
     //
     // returns:
     // [{"id":NUM}...]
-
+    let s = store.clone();
+    router.get("/clusters/:id/watchpoints", middleware! {|req, mut res|
+        res.set(MediaType::Json);
+        respond(watchpoints(&*s, req.param("id")))
+    });
     // [POST] /clusters/:id/watchpoints
     //
     // params:
     // {"type":ENUM, "ident":ENUM(STRUCT)}
     // returns:
     // ()
-
+    //
+    // kept in the store and watched along with those in the config file,
+    // except for commands.
+    let s = store.clone();
+    let sp = spool.clone();
+    router.post("/clusters/:id/watchpoints", middleware! {|req, mut res|
+        let mut body = String::new();
+        let _ = req.origin.read_to_string(&mut body);
+        res.set(MediaType::Json);
+        respond(add_watchpoint(&*s, &sp, req.param("id"), &body))
+    });
     // [GET] /clusters/:id/watchpoints/:id
     //
     // returns:
     // {"id":NUM, "type":ENUM, "ident":ENUM(STRUCT), "histories":ARRAY(NUM)}
-
+    let s = store.clone();
+    router.get("/clusters/:id/watchpoints/:watchpoint_id", middleware! {|req, mut res|
+        res.set(MediaType::Json);
+        respond(watchpoint(&*s, req.param("id"), req.param("watchpoint_id")))
+    });
     // [GET] /clusters/:id/histories
     //
     // returns:
     // [{"id":NUM},...]
-
+    let s = store.clone();
+    router.get("/clusters/:id/histories", middleware! {|req, mut res|
+        res.set(MediaType::Json);
+        respond(histories(&*s, req.param("id")))
+    });
     // [DELETE] /clusters/:id/histories
     //
     // returns:
     // ()
-
+    let s = store.clone();
+    let l = logs.clone();
+    router.delete("/clusters/:id/histories", middleware! {|req, mut res|
+        res.set(MediaType::Json);
+        respond(delete_histories(&*s, &l, req.param("id")))
+    });
     // [GET] /clusters/:id/histories/:id
     //
     // returns:
     // {"id":NUM, "config_id":NUM, "results":ARRAY(STRUCT), "passed":bool}
-
+    let s = store.clone();
+    router.get("/clusters/:id/histories/:history_id", middleware! {|req, mut res|
+        res.set(MediaType::Json);
+        respond(history(&*s, req.param("id"), req.param("history_id")))
+    });
+    // [POST] /clusters/:id/runs
+    //
+    // params:
+    // {"requested_by":STRING} (optional)
+    // returns:
+    // {"id":NUM, "state":ENUM, "causes":ARRAY(STRUCT), "results":ARRAY(STRUCT), "passed":bool}
+    let s = store.clone();
+    let sp = spool.clone();
+    router.post("/clusters/:id/runs", middleware! {|req, mut res|
+        let mut body = String::new();
+        let _ = req.origin.read_to_string(&mut body);
+        let origin = req.origin.remote_addr.to_string();
+        res.set(MediaType::Json);
+        respond(request_run(&*s, &sp, req.param("id"), &body, &origin))
+    });
+    // [GET] /clusters/:id/runs/:id
+    //
+    // returns:
+    // {"id":NUM, "state":ENUM, "causes":ARRAY(STRUCT), "results":ARRAY(STRUCT), "passed":bool}
+    let s = store.clone();
+    router.get("/clusters/:id/runs/:run_id", middleware! {|req, mut res|
+        res.set(MediaType::Json);
+        respond(run_status(&*s, req.param("id"), req.param("run_id")))
+    });
+    // [DELETE] /clusters/:id/runs/:id
+    //
+    // returns:
+    // ()
+    let s = store.clone();
+    let sp = spool.clone();
+    router.delete("/clusters/:id/runs/:run_id", middleware! {|req, mut res|
+        res.set(MediaType::Json);
+        respond(cancel_run(&*s, &sp, req.param("id"), req.param("run_id")))
+    });
+    // [GET] /clusters/:id/runs/:id/output
+    //
+    // returns (text/event-stream):
+    // events "start", "stdout", "stderr" and "end" of each exec,
+    // then "done" once the run is over. 503 if too many streams are open.
+    router.get("/clusters/:id/runs/:run_id/output", ExecOutputStream {
+        store: store.clone(),
+        logs: logs.clone(),
+        slots: slots.clone(),
+    });
+    // [GET] /clusters/:id/runs/:id/consoles/:id
+    //
+    // returns (text/event-stream):
+    // events "console" with serial console output of the host as JSON
+    // strings, then "done" once the run is over. 503 if too many streams
+    // are open.
+    router.get("/clusters/:id/runs/:run_id/consoles/:host_id", ConsoleStream {
+        store: store.clone(),
+        logs: logs.clone(),
+        slots: slots.clone(),
+    });
     // [GET] /clusters/:id/hosts
     //
     // returns:
     // [{"id",NUM},...]
-
+    let s = store.clone();
+    router.get("/clusters/:id/hosts", middleware! {|req, mut res|
+        res.set(MediaType::Json);
+        respond(hosts(&*s, req.param("id")))
+    });
     // [GET] /clusters/:id/hosts/:id
     //
     // return:
     // {"id": NUM, "name":STRING, "state":ENUM, "histories": ARRAY(NUM)}
-
+    let s = store.clone();
+    let hv = hypervisor.clone();
+    router.get("/clusters/:id/hosts/:host_id", middleware! {|req, mut res|
+        res.set(MediaType::Json);
+        respond(host(&*s, &hv, req.param("id"), req.param("host_id")))
+    });
     // [GET] /clusters/:id/hosts/:id/histories
     //
     // returns:
-    // {"id": NUM, "config_id":NUM, "results":ARRAY(STRUCT), "passed":bool}
+    // [{"id": NUM, "config_id":NUM, "results":ARRAY(STRUCT), "passed":bool},...]
+    let s = store.clone();
+    router.get("/clusters/:id/hosts/:host_id/histories", middleware! {|req, mut res|
+        res.set(MediaType::Json);
+        respond(host_histories(&*s, req.param("id"), req.param("host_id")))
+    });
 
     server.mount("/api/v1/", router);
     let listening = server.listen("127.0.0.1:4472")
@@ -0,0 +1,197 @@
+use hyper::net::Streaming;
+use libc;
+use nickel::{Middleware, MiddlewareResult, NickelError, Request, Response};
+use nickel::Action::Halt;
+use nickel::status::StatusCode;
+use nix::unistd::sleep;
+use serde_json;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use ::flota::runlog::RunLogs;
+use ::flota::store::{History, RunState, Store};
+use ::virt::domain::Domain;
+use super::{find_cluster, find_run};
+
+// Server-Sent Events. Payloads are always JSON, thus single-lined.
+fn send<W: Write>(w: &mut W, event: &str, data: &str) -> io::Result<()> {
+    try!(write!(w, "event: {}\ndata: {}\n\n", event, data));
+    w.flush()
+}
+
+fn in_progress(store: &Store, history: &History) -> bool {
+    store.histories(history.cluster_id)
+         .ok()
+         .and_then(|hs| hs.into_iter().find(|h| h.id == history.id))
+         .map(|h| h.state == RunState::Pending || h.state == RunState::Running)
+         .unwrap_or(false)
+}
+
+// Each stream holds a worker of the server for as long as it is open,
+// so some of them are always left for the rest of the api. The server
+// has as many workers as hyper's default, i.e. 5/4 per cpu.
+fn max_streams() -> usize {
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    let workers = if cpus > 0 { cpus as usize * 5 / 4 } else { 1 };
+    workers / 2
+}
+
+/// Count of streams open, shared by every kind of them.
+#[derive(Clone, Default)]
+pub struct StreamSlots {
+    open: Arc<AtomicUsize>,
+}
+
+// Taken for as long as the stream is open.
+struct StreamSlot {
+    open: Arc<AtomicUsize>,
+}
+
+impl StreamSlots {
+    fn take(&self) -> Option<StreamSlot> {
+        if self.open.fetch_add(1, Ordering::SeqCst) >= max_streams() {
+            self.open.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(StreamSlot { open: self.open.clone() })
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn start_event_stream<'mw, D>(mut res: Response<'mw, D>)
+                              -> Result<Response<'mw, D, Streaming>, NickelError<'mw, D>> {
+    res.headers_mut().set_raw("Content-Type", vec![b"text/event-stream".to_vec()]);
+    res.headers_mut().set_raw("Cache-Control", vec![b"no-cache".to_vec()]);
+    res.start()
+}
+
+// [GET] /clusters/:id/runs/:id/output
+//
+// Output of each exec of the run as it arrives, until the run is over.
+pub struct ExecOutputStream {
+    pub store: Arc<Store>,
+    pub logs: RunLogs,
+    pub slots: StreamSlots,
+}
+
+impl<D> Middleware<D> for ExecOutputStream {
+    fn invoke<'mw, 'conn>(&'mw self, req: &mut Request<'mw, 'conn, D>, res: Response<'mw, D>)
+                          -> MiddlewareResult<'mw, D> {
+        let history = match find_run(&*self.store, req.param("id"), req.param("run_id")) {
+            Ok(h) => h,
+            Err((status, msg)) => return res.error(status, msg),
+        };
+        let _slot = match self.slots.take() {
+            Some(slot) => slot,
+            None => return res.error(StatusCode::ServiceUnavailable, "too many streams open"),
+        };
+        let mut stream = try!(start_event_stream(res));
+        let mut offset = 0;
+        loop {
+            // checked before reading so that the last events are never missed.
+            let running = in_progress(&*self.store, &history);
+            let (events, next) = match self.logs.read_from(history.id, offset) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("{}", e);
+                    break;
+                }
+            };
+            offset = next;
+            for event in events.iter() {
+                if send(&mut stream, event.name(), &serde_json::to_string(event).unwrap()).is_err() {
+                    // client has gone
+                    return Ok(Halt(stream));
+                }
+            }
+            if !running {
+                let _ = send(&mut stream, "done", "null");
+                break;
+            }
+            sleep(1);
+        }
+        Ok(Halt(stream))
+    }
+}
+
+// [GET] /clusters/:id/runs/:id/consoles/:id
+//
+// Serial console of the host as it is written, until the run is over.
+pub struct ConsoleStream {
+    pub store: Arc<Store>,
+    pub logs: RunLogs,
+    pub slots: StreamSlots,
+}
+
+impl<D> Middleware<D> for ConsoleStream {
+    fn invoke<'mw, 'conn>(&'mw self, req: &mut Request<'mw, 'conn, D>, res: Response<'mw, D>)
+                          -> MiddlewareResult<'mw, D> {
+        let history = match find_run(&*self.store, req.param("id"), req.param("run_id")) {
+            Ok(h) => h,
+            Err((status, msg)) => return res.error(status, msg),
+        };
+        let hostname = match find_cluster(&*self.store, req.param("id")) {
+            Ok(cluster) => {
+                let host_id = req.param("host_id").and_then(|id| id.parse::<u64>().ok());
+                match cluster.hosts.into_iter().find(|h| Some(h.id) == host_id) {
+                    Some(h) => h.hostname,
+                    None => return res.error(StatusCode::NotFound, "no such host"),
+                }
+            },
+            Err((status, msg)) => return res.error(status, msg),
+        };
+        let path = Domain::serial_log_path(&hostname);
+        let _slot = match self.slots.take() {
+            Some(slot) => slot,
+            None => return res.error(StatusCode::ServiceUnavailable, "too many streams open"),
+        };
+        let mut stream = try!(start_event_stream(res));
+        // none until the run starts, from where the log was at then on.
+        let mut offset = None;
+        loop {
+            let running = in_progress(&*self.store, &history);
+            if offset.is_none() {
+                offset = match self.logs.console_offset(history.id, &hostname) {
+                    Ok(o) => o,
+                    Err(e) => {
+                        error!("{}", e);
+                        break;
+                    }
+                };
+            }
+            if let (Some(mut pos), Ok(mut f)) = (offset, File::open(&path)) {
+                // the log starts over whenever the domain boots.
+                let len = f.metadata().map(|m| m.len()).unwrap_or(0);
+                if len < pos {
+                    pos = 0;
+                }
+                let mut buf = Vec::new();
+                if f.seek(SeekFrom::Start(pos)).is_ok() && f.read_to_end(&mut buf).is_ok() {
+                    offset = Some(pos + buf.len() as u64);
+                    if !buf.is_empty() {
+                        let data = serde_json::to_string(&String::from_utf8_lossy(&buf)
+                                                              .into_owned()).unwrap();
+                        if send(&mut stream, "console", &data).is_err() {
+                            // client has gone
+                            return Ok(Halt(stream));
+                        }
+                    }
+                }
+            }
+            if !running {
+                let _ = send(&mut stream, "done", "null");
+                break;
+            }
+            sleep(1);
+        }
+        Ok(Halt(stream))
+    }
+}
@@ -0,0 +1,317 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use time;
+use ::consts::*;
+use ::distro::Distros;
+use ::exec::session::ssh::known_hosts_file;
+use ::flota::config::Config;
+use ::flota::config::cluster::Cluster;
+use ::flota::config::setting::Setting;
+use ::flota::config::template::{Ingredient, Template as TemplateConfig};
+use ::flota::entity::template::Template;
+use ::flota::hash;
+use ::flota::manager::Manager;
+use ::flota::runlog::RunLogs;
+use ::flota::spool::Spool;
+use ::flota::store;
+use ::flota::store::{ClusterRecord, History, RunState};
+use ::flota::test::Cause;
+use ::util::errors::*;
+use ::virt;
+use ::virt::ResourceBlend;
+use ::virt::conn::Conn;
+use ::virt::domain::Domain;
+use ::virt::network::Network;
+use ::virt::storage::pool::StoragePool;
+
+pub fn usage() -> String {
+    format!("Commands:
+    templates list
+    templates show [TEMPLATE]   show the template, or available distros if omitted
+    templates build [TEMPLATE]  build the template, or all of them if omitted
+    templates delete TEMPLATE
+    clusters list
+    run CLUSTER                 run the cluster once regardless of its watchpoints
+    status
+    history CLUSTER
+
+Without any command, {} runs as usual.
+", *PROGNAME)
+}
+
+// Same default resources as the main loop sets up.
+fn with_default_resources<T, F>(setting: &Setting, f: F) -> Result<T>
+    where F: FnOnce(&ResourceBlend) -> Result<T>
+{
+    let conn = try!(Conn::open(&setting.hypervisor));
+    let pool = try!(StoragePool::ensure(&conn,
+                                        &setting.default_storage_pool_name,
+                                        &setting.pool_root));
+    let br_ip = setting.default_network.nth_sibling(1);
+    let network = Network::ensure_default(&conn, &br_ip, true);
+    let mut resources = ResourceBlend::new(&conn);
+    resources.put_network(&network);
+    resources.put_pool(&pool);
+    f(&resources)
+}
+
+// Name of the image domain of the template, if its distro is known.
+fn image_ident(template: &TemplateConfig) -> Option<String> {
+    match template.ingredient {
+        Ingredient::OffTheShelf { ref distro } => {
+            if Distros::available().iter().any(|&(d, a)| {
+                d == distro.as_str() && a == template.arch.as_str()
+            }) {
+                Distros::search(distro, &template.arch).ok().map(|d| d.ident())
+            } else {
+                None
+            }
+        },
+        Ingredient::Custom { .. } => None,
+    }
+}
+
+fn image_state(conn: &Option<Conn>, template: &TemplateConfig) -> &'static str {
+    match (conn, image_ident(template)) {
+        (&Some(ref conn), Some(ref ident)) => {
+            if Domain::find(ident, conn).is_some() { "built" } else { "not built" }
+        },
+        _ => "unknown",
+    }
+}
+
+fn find_cluster<'a>(config: &'a Config, name: &str) -> Result<&'a Cluster> {
+    config.clusters
+          .iter()
+          .find(|c| c.name == name)
+          .map(|c| &**c)
+          .ok_or(format!("no such cluster: {}", name).into())
+}
+
+fn find_template<'a>(config: &'a Config, name: &str) -> Result<&'a TemplateConfig> {
+    config.templates
+          .iter()
+          .find(|t| t.name == name)
+          .map(|t| &**t)
+          .ok_or(format!("no such template: {}", name).into())
+}
+
+fn format_millis(ms: u64) -> String {
+    let tm = time::at(time::Timespec::new((ms / 1000) as i64, ((ms % 1000) * 1000000) as i32));
+    format!("{}", tm.rfc3339())
+}
+
+fn format_cause(cause: &Cause) -> String {
+    match *cause {
+        Cause::FirstRun => "first run".to_string(),
+        Cause::WatchPoint { ref changed, .. } if !changed.is_empty() => {
+            format!("watchpoint ({})", changed.join(", "))
+        },
+        Cause::WatchPoint { .. } => "watchpoint".to_string(),
+        Cause::Scheduled { at } => format!("scheduled at {}", format_millis(at)),
+        Cause::Manual { ref requested_by } => format!("requested by {}", requested_by),
+    }
+}
+
+fn format_history(history: &History) -> String {
+    format!("{}  {:<9}  {:<6}  {}/{} passed  ({}){}",
+            format_millis(history.id),
+            format!("{:?}", history.state),
+            if history.passed { "ok" } else { "FAILED" },
+            history.results.iter().filter(|r| r.passed).count(),
+            history.results.len(),
+            history.causes.iter().map(format_cause).collect::<Vec<_>>().join(", "),
+            history.error.as_ref().map(|e| format!(": {}", e)).unwrap_or(String::new()))
+}
+
+fn templates_list(config: &Config) -> Result<()> {
+    let conn = Conn::open(&config.setting.hypervisor).ok();
+    for template in config.templates.iter() {
+        println!("{:<20} {:<8} {}", template.name, template.arch, image_state(&conn, template));
+    }
+    Ok(())
+}
+
+fn templates_show(config: &Config, name: Option<&str>) -> Result<()> {
+    let name = match name {
+        Some(name) => name,
+        None => {
+            println!("available distros:");
+            for &(distro, arch) in Distros::available().iter() {
+                println!("    {:<12} {}", distro, arch);
+            }
+            return Ok(());
+        }
+    };
+    let template = try!(find_template(config, name));
+    let conn = Conn::open(&config.setting.hypervisor).ok();
+    println!("name:        {}", template.name);
+    println!("arch:        {}", template.arch);
+    match template.ingredient {
+        Ingredient::OffTheShelf { ref distro } => {
+            println!("distro:      {}", distro);
+        },
+        Ingredient::Custom { ref iso, ref vmlinuz, ref initrd, .. } => {
+            println!("iso:         {}", iso.as_str());
+            println!("vmlinuz:     {}", vmlinuz.as_str());
+            println!("initrd:      {}", initrd.as_str());
+        },
+    }
+    println!("kickstart:   {}", if template.ks.is_some() { "custom" } else { "default" });
+    println!("mgmt user:   {}", template.mgmt_user);
+    println!("private key: {}", template.mgmt_user_ssh_private_key.display());
+    println!("public key:  {}", template.mgmt_user_ssh_public_key.display());
+    println!("image:       {}", image_state(&conn, template));
+    Ok(())
+}
+
+fn templates_build(config: &Config, name: Option<&str>) -> Result<()> {
+    try!(::verify_env());
+    with_default_resources(&config.setting, |resources| {
+        for template in config.templates.iter() {
+            if name.map(|n| n != template.name).unwrap_or(false) {
+                continue;
+            }
+            println!("building {}...", template.name);
+            let built = try!(Template::from_config(resources, template));
+            println!("built {}: {}", built.name, built.path_disk);
+        }
+        Ok(())
+    })
+}
+
+fn templates_delete(config: &Config, name: &str) -> Result<()> {
+    let template = try!(find_template(config, name));
+    with_default_resources(&config.setting, |resources| {
+        try!(Template::delete_image(resources, template));
+        println!("deleted {}", template.name);
+        Ok(())
+    })
+}
+
+fn clusters_list(config: &Config) -> Result<()> {
+    for cluster in config.clusters.iter() {
+        println!("{:<20} {} hosts, {} watchpoints  (id: {})",
+                 cluster.name,
+                 cluster.hosts.len(),
+                 cluster.watchpoints.len(),
+                 hash(&cluster.name));
+    }
+    Ok(())
+}
+
+fn run(config: &mut Config, name: &str) -> Result<()> {
+    try!(::verify_env());
+    let store = try!(store::open(&config.setting));
+    try!(config.add_stored_watchpoints(&*store));
+    try!(config.save(&*store));
+    let cluster = try!(find_cluster(config, name));
+    let manager = Manager::new(store.clone(), try!(Spool::open()), try!(RunLogs::open()));
+    let requested_by = env::var("USER").unwrap_or("cli".to_string());
+    let mut history = History::requested(&ClusterRecord::new(cluster), &requested_by);
+    let ret = with_default_resources(&config.setting, |resources| {
+        // only the templates its hosts need.
+        let mut templates = Vec::new();
+        for template in config.templates.iter() {
+            if cluster.hosts.iter().any(|h| h.template.name == template.name) {
+                templates.push(Arc::new(try!(Template::from_config(resources, template))));
+            }
+        }
+        manager.run(cluster, &templates, &mut history)
+    });
+    println!("{}", format_history(&history));
+    ret
+}
+
+fn status(config: &Config) -> Result<()> {
+    let store = try!(store::open(&config.setting));
+    let conn = Conn::open(&config.setting.hypervisor).ok();
+    for cluster in config.clusters.iter() {
+        match try!(store.histories(hash(&cluster.name))).first() {
+            Some(history) => println!("{}: {}", cluster.name, format_history(history)),
+            None => println!("{}: never run", cluster.name),
+        }
+        for host in cluster.hosts.iter() {
+            let state = match conn {
+                Some(ref conn) => {
+                    match Domain::find(&host.hostname, conn) {
+                        Some(dom) => dom.state().map(|s| format!("{:?}", s))
+                                         .unwrap_or("unknown".to_string()),
+                        None => "undefined".to_string(),
+                    }
+                },
+                None => "unknown".to_string(),
+            };
+            println!("    {:<20} {}", host.hostname, state);
+        }
+    }
+    Ok(())
+}
+
+fn history(config: &Config, name: &str) -> Result<()> {
+    let cluster = try!(find_cluster(config, name));
+    let store = try!(store::open(&config.setting));
+    let histories = try!(store.histories(hash(&cluster.name)));
+    if histories.is_empty() {
+        println!("never run");
+    }
+    for history in histories.iter() {
+        println!("{}", format_history(history));
+        if history.state == RunState::Running {
+            continue;
+        }
+        for result in history.results.iter().filter(|r| !r.passed) {
+            println!("    {} on {}: {}",
+                     if result.skipped { "skipped" } else { "failed" },
+                     result.host,
+                     result.command);
+            if let Some(ref failure) = result.failure {
+                println!("        unsatisfied {}", failure.matcher);
+                for line in failure.detail.lines() {
+                    println!("        {}", line);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `--clean` removes hosts, `--reset` everything else of ours as well,
+/// i.e. template images, networks, the pool, whatever is stored and
+/// host keys recorded.
+pub fn clean(config_path: &Path, reset: bool) -> Result<()> {
+    let config = try!(Config::from_toml_file(config_path));
+    let conn = try!(Conn::open(&config.setting.hypervisor));
+    for removed in try!(virt::clean(&conn, &config.setting.default_storage_pool_name, reset)) {
+        println!("removed {}", removed);
+    }
+    if reset {
+        try!(try!(store::open(&config.setting)).clear());
+        try!(try!(Spool::open()).clear());
+        try!(try!(RunLogs::open()).clear());
+        println!("cleared histories");
+        if known_hosts_file().exists() {
+            try!(fs::remove_file(known_hosts_file()));
+            println!("cleared known hosts");
+        }
+    }
+    Ok(())
+}
+
+pub fn run_command(config_path: &Path, args: &[String]) -> Result<()> {
+    let mut config = try!(Config::from_toml_file(config_path));
+    let arg = |i: usize| args.get(i).map(|s| s.as_str());
+    match (arg(0), arg(1), arg(2)) {
+        (Some("templates"), Some("list"), None) => templates_list(&config),
+        (Some("templates"), Some("show"), name) => templates_show(&config, name),
+        (Some("templates"), Some("build"), name) => templates_build(&config, name),
+        (Some("templates"), Some("delete"), Some(name)) => templates_delete(&config, name),
+        (Some("clusters"), Some("list"), None) => clusters_list(&config),
+        (Some("run"), Some(name), None) => run(&mut config, name),
+        (Some("status"), None, None) => status(&config),
+        (Some("history"), Some(name), None) => history(&config, name),
+        _ => Err(format!("invalid command: {}\n\n{}", args.join(" "), usage()).into()),
+    }
+}
@@ -21,7 +21,7 @@ use ::flota::config::cluster::host::Host as HostConfig;
 use ::util::*;
 use ::util::errors::*;
 use ::util::notify::tailf_background;
-use ::virt::ResourceBlend;
+use ::virt::{ResourceBlend, Role};
 use ::virt::conn::Conn;
 use ::virt::domain::Domain;
 use ::virt::network::Network;
@@ -135,6 +135,9 @@ impl distro::Base for CentOS6_x8664 {
     fn arch(&self) -> String {
         "x86_64".to_string()
     }
+    fn ident(&self) -> String {
+        IDENT.to_string()
+    }
     fn build_image(&self,
                    name: Option<&str>,
                    conn: &Conn,
@@ -208,6 +211,7 @@ impl distro::Base for CentOS6_x8664 {
                     let mut x = xE!("domain", type => "kvm");
                     x.tag(xE!("name"))
                         .text(dom_name.to_owned().into());
+                    x.tag(Role::Template.metadata());
                     x.tag(xE!("memory", unit => "MiB"))
                         .text((MEM_ON_INSTALL).to_string().into());
                     x.tag(xE!("vcpu", placement => "static"))
@@ -326,6 +330,7 @@ impl distro::Base for CentOS6_x8664 {
                     let mut x = xE!("domain", type => "kvm");
                     x.tag(xE!("name"))
                         .text(dom_name.into());
+                    x.tag(Role::Template.metadata());
                     x.tag(xE!("memory", unit => "KiB"))
                         .text((mem_mb * 1024).to_string().into());
                     x.tag(xE!("vcpu", placement => "static"))
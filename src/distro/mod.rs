@@ -18,6 +18,15 @@ pub trait Base : fmt::Debug {
     fn distro(&self) -> String;
     fn release(&self) -> String;
     fn arch(&self) -> String;
+    /// Name of the image domain unless named otherwise.
+    fn ident(&self) -> String;
+    /// Whether the image comes with a qemu-guest-agent we can run commands
+    /// through, i.e. 2.5 or later for guest-exec, with neither it nor the
+    /// guest-file-* commands blacklisted. Neither CentOS 6 (0.12) nor
+    /// openSUSE 13 (2.1) does.
+    fn has_guest_agent(&self) -> bool {
+        false
+    }
     fn build_image(&self,
                    name: Option<&str>,
                    conn: &Conn,
@@ -76,15 +85,41 @@ pub mod opensuse;
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Distros;
 impl Distros {
-    pub fn search(ident: &str, arch: &str) -> Box<Distro> {
+    /// Distros which `search` knows of, as (distro, arch).
+    pub fn available() -> Vec<(&'static str, &'static str)> {
+        vec![
+            ("centos6", "x86_64"),
+            ("opensuse13", "x86_64"),
+        ]
+    }
+    pub fn search(ident: &str, arch: &str) -> Result<Box<Distro>> {
         match (ident, arch) {
-            ("centos6", "x86_64") => Box::new(centos::release_6::x86_64::CentOS6_x8664::new()),
-            ("opensuse13", "x86_64") => Box::new(opensuse::release_13::x86_64::OpenSUSE13_x8664::new()),
-            _ => unimplemented!(),
+            ("centos6", "x86_64") => Ok(Box::new(centos::release_6::x86_64::CentOS6_x8664::new())),
+            ("opensuse13", "x86_64") => Ok(Box::new(opensuse::release_13::x86_64::OpenSUSE13_x8664::new())),
+            _ => Err(format!("unknown distro: {} ({})", ident, arch).into()),
+        }
+    }
+    /// Distro the ingredient of the template points to.
+    pub fn of(template: &config::template::Template) -> Result<Box<Distro>> {
+        match template.ingredient {
+            config::template::Ingredient::OffTheShelf { ref distro } => {
+                Distros::search(distro, &template.arch)
+            },
+            // XXX: linux is not the only choice
+            config::template::Ingredient::Custom {
+                ref iso,
+                ref iso_md5sum,
+                ref vmlinuz,
+                ref initrd,
+            } => {
+                Distros::custom(iso, iso_md5sum, vmlinuz, initrd)
+            }
         }
     }
     // XXX: linux is not the only choice
-    pub fn custom(iso: &Url, iso_md5sum: &Option<Url>, vmlinuz: &Url, initrd: &Url) -> Box<Distro> {
-        unimplemented!()
+    #[allow(unused_variables)]
+    pub fn custom(iso: &Url, iso_md5sum: &Option<Url>, vmlinuz: &Url, initrd: &Url)
+                  -> Result<Box<Distro>> {
+        Err(format!("custom ingredients are not supported yet: {}", iso.as_str()).into())
     }
 }
@@ -0,0 +1,502 @@
+use difference::{diff, Difference};
+use regex::Regex;
+use serde;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde_json;
+use serde_json::Value;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use toml;
+use ::util::errors::*;
+use super::Output;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn of<'a>(&self, output: &'a Output) -> &'a str {
+        let s = match *self {
+            Stream::Stdout => &output.stdout,
+            Stream::Stderr => &output.stderr,
+        };
+        s.as_ref().map(|s| s.as_str()).unwrap_or("")
+    }
+}
+
+impl fmt::Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Stream::Stdout => write!(f, "stdout"),
+            Stream::Stderr => write!(f, "stderr"),
+        }
+    }
+}
+
+/// Regex compiled once as the config is read, and compared, hashed and
+/// serialized as its pattern.
+#[derive(Clone)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Self> {
+        match Regex::new(pattern) {
+            Ok(re) => Ok(Pattern(re)),
+            Err(e) => Err(format!("invalid regex {:?}: {}", pattern, e).into()),
+        }
+    }
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Pattern({:?})", self.as_str())
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for Pattern {}
+
+impl Hash for Pattern {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S>(&self, serializer: &mut S) -> ::std::result::Result<(), S::Error>
+        where S: Serializer {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl Deserialize for Pattern {
+    fn deserialize<D>(deserializer: &mut D) -> ::std::result::Result<Pattern, D::Error>
+        where D: Deserializer {
+        deserializer.deserialize_str(PatternVisitor)
+    }
+}
+
+struct PatternVisitor;
+
+impl serde::de::Visitor for PatternVisitor {
+    type Value = Pattern;
+    fn visit_str<E>(&mut self, v: &str) -> ::std::result::Result<Self::Value, E>
+        where E: serde::Error {
+        Pattern::new(v).map_err(|e| E::custom(e.to_string()))
+    }
+}
+
+// Expectation on an exec output. In the exec toml, stdout and stderr take
+// either a string to be equal to, or a table of any of:
+//
+//   regex = "^ok$"
+//   contains = "foo"               or an array of them
+//   not_contains = ["bar", "baz"]
+//   json = { "$.items[0].name" = "foo" }
+//   lines = 3                      or { min = 1, max = 5 }
+//
+// while status takes an integer, an array of integers or { min, max }.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Matcher {
+    Equals {
+        stream: Stream,
+        value: String,
+    },
+    Regex {
+        stream: Stream,
+        pattern: Pattern,
+    },
+    Contains {
+        stream: Stream,
+        value: String,
+    },
+    NotContains {
+        stream: Stream,
+        value: String,
+    },
+    /// `value` is kept as JSON text.
+    JsonPath {
+        stream: Stream,
+        path: String,
+        value: String,
+    },
+    Lines {
+        stream: Stream,
+        min: Option<usize>,
+        max: Option<usize>,
+    },
+    StatusIn(Vec<i32>),
+    StatusRange {
+        min: Option<i32>,
+        max: Option<i32>,
+    },
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Matcher::Equals { stream, ref value } => write!(f, "{} == {:?}", stream, value),
+            Matcher::Regex { stream, ref pattern } => {
+                write!(f, "{} =~ /{}/", stream, pattern.as_str())
+            },
+            Matcher::Contains { stream, ref value } => {
+                write!(f, "{} contains {:?}", stream, value)
+            },
+            Matcher::NotContains { stream, ref value } => {
+                write!(f, "{} does not contain {:?}", stream, value)
+            },
+            Matcher::JsonPath { stream, ref path, ref value } => {
+                write!(f, "{} at {} == {}", stream, path, value)
+            },
+            Matcher::Lines { stream, min, max } => {
+                write!(f, "{} has {} lines", stream, format_range(min, max))
+            },
+            Matcher::StatusIn(ref set) => write!(f, "status in {:?}", set),
+            Matcher::StatusRange { min, max } => {
+                write!(f, "status in {}", format_range(min, max))
+            },
+        }
+    }
+}
+
+fn format_range<T: fmt::Display>(min: Option<T>, max: Option<T>) -> String {
+    match (min, max) {
+        (Some(min), Some(max)) => format!("{}..{}", min, max),
+        (Some(min), None) => format!("{}..", min),
+        (None, Some(max)) => format!("..{}", max),
+        (None, None) => "..".to_string(),
+    }
+}
+
+fn in_range<T: PartialOrd>(n: T, min: &Option<T>, max: &Option<T>) -> bool {
+    min.as_ref().map(|m| n >= *m).unwrap_or(true) && max.as_ref().map(|m| n <= *m).unwrap_or(true)
+}
+
+/// Which matcher an output failed, and why.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MatchFailure {
+    pub matcher: String,
+    pub detail: String,
+}
+
+// "-" for expected lines missing, "+" for unexpected ones.
+fn line_diff(expected: &str, actual: &str) -> String {
+    let (_, changeset) = diff(expected, actual, "\n");
+    let mut lines = Vec::new();
+    for change in changeset.iter() {
+        let (mark, text) = match *change {
+            Difference::Same(ref s) => (" ", s),
+            Difference::Rem(ref s) => ("-", s),
+            Difference::Add(ref s) => ("+", s),
+        };
+        for line in text.split('\n') {
+            lines.push(format!("{}{}", mark, line));
+        }
+    }
+    lines.join("\n")
+}
+
+fn lookup_json<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    let mut cur = value;
+    for key in path.trim_left_matches('$')
+                   .split(|c| c == '.' || c == '[' || c == ']')
+                   .filter(|k| !k.is_empty()) {
+        let next = match *cur {
+            Value::Object(ref map) => map.get(key),
+            Value::Array(ref vals) => key.parse::<usize>().ok().and_then(|i| vals.get(i)),
+            _ => None,
+        };
+        cur = match next {
+            Some(v) => v,
+            None => return None,
+        };
+    }
+    Some(cur)
+}
+
+fn toml_to_json(val: &toml::Value) -> String {
+    match *val {
+        toml::Value::String(ref s) | toml::Value::Datetime(ref s) => {
+            serde_json::to_string(s).unwrap()
+        },
+        toml::Value::Integer(i) => i.to_string(),
+        // as JSON has it, e.g. 1.0 rather than 1.
+        toml::Value::Float(f) => serde_json::to_string(&f).unwrap(),
+        toml::Value::Boolean(b) => b.to_string(),
+        toml::Value::Array(ref vals) => {
+            format!("[{}]", vals.iter().map(toml_to_json).collect::<Vec<_>>().join(","))
+        },
+        toml::Value::Table(ref table) => {
+            format!("{{{}}}",
+                    table.iter()
+                         .map(|(k, v)| format!("{}:{}", serde_json::to_string(k).unwrap(),
+                                                toml_to_json(v)))
+                         .collect::<Vec<_>>()
+                         .join(","))
+        },
+    }
+}
+
+fn strings_of(key: &str, val: &toml::Value) -> Result<Vec<String>> {
+    match *val {
+        toml::Value::String(ref s) => Ok(vec![s.clone()]),
+        toml::Value::Array(ref vals) => {
+            vals.iter()
+                .map(|v| v.as_str().map(|s| s.to_string())
+                          .ok_or(format!("`{}` must be strings", key).into()))
+                .collect()
+        },
+        _ => Err(format!("`{}` must be a string or an array of them", key).into()),
+    }
+}
+
+fn bounds_of(key: &str, val: &toml::Value) -> Result<(Option<i64>, Option<i64>)> {
+    match *val {
+        toml::Value::Integer(n) => Ok((Some(n), Some(n))),
+        toml::Value::Table(_) => {
+            Ok((val.lookup("min").and_then(|v| v.as_integer()),
+                val.lookup("max").and_then(|v| v.as_integer())))
+        },
+        _ => Err(format!("`{}` must be an integer or {{ min, max }}", key).into()),
+    }
+}
+
+impl Matcher {
+    /// Matchers out of the table given to stdout or stderr.
+    pub fn from_toml(stream: Stream, tml: &toml::Value) -> Result<Vec<Matcher>> {
+        let table = match *tml {
+            toml::Value::String(ref s) => {
+                return Ok(vec![Matcher::Equals { stream: stream, value: s.clone() }]);
+            },
+            toml::Value::Table(ref table) => table,
+            _ => return Err(format!("`{}` must be a string or a table", stream).into()),
+        };
+        let mut matchers = Vec::new();
+        for (key, val) in table.iter() {
+            match key.as_str() {
+                "equals" => {
+                    for s in try!(strings_of(key, val)) {
+                        matchers.push(Matcher::Equals { stream: stream, value: s });
+                    }
+                },
+                "regex" => {
+                    for s in try!(strings_of(key, val)) {
+                        matchers.push(Matcher::Regex {
+                            stream: stream,
+                            pattern: try!(Pattern::new(&s)),
+                        });
+                    }
+                },
+                "contains" => {
+                    for s in try!(strings_of(key, val)) {
+                        matchers.push(Matcher::Contains { stream: stream, value: s });
+                    }
+                },
+                "not_contains" => {
+                    for s in try!(strings_of(key, val)) {
+                        matchers.push(Matcher::NotContains { stream: stream, value: s });
+                    }
+                },
+                "json" => {
+                    match *val {
+                        toml::Value::Table(ref paths) => {
+                            for (path, value) in paths.iter() {
+                                matchers.push(Matcher::JsonPath {
+                                    stream: stream,
+                                    path: path.clone(),
+                                    value: toml_to_json(value),
+                                });
+                            }
+                        },
+                        _ => return Err("`json` must be a table of path = value".into()),
+                    }
+                },
+                "lines" => {
+                    let (min, max) = try!(bounds_of(key, val));
+                    if min.unwrap_or(0) < 0 || max.unwrap_or(0) < 0 {
+                        return Err("`lines` must not be negative".into());
+                    }
+                    matchers.push(Matcher::Lines {
+                        stream: stream,
+                        min: min.map(|n| n as usize),
+                        max: max.map(|n| n as usize),
+                    });
+                },
+                _ => return Err(format!("unknown matcher for {}: {}", stream, key).into()),
+            }
+        }
+        Ok(matchers)
+    }
+    /// Matcher out of what is given to status.
+    pub fn status_from_toml(tml: &toml::Value) -> Result<Matcher> {
+        match *tml {
+            toml::Value::Integer(n) => Ok(Matcher::StatusIn(vec![n as i32])),
+            toml::Value::Array(ref vals) => {
+                let mut set = Vec::new();
+                for val in vals.iter() {
+                    match val.as_integer() {
+                        Some(n) => set.push(n as i32),
+                        None => return Err("`status` must be integers".into()),
+                    }
+                }
+                Ok(Matcher::StatusIn(set))
+            },
+            _ => {
+                let (min, max) = try!(bounds_of("status", tml));
+                Ok(Matcher::StatusRange {
+                    min: min.map(|n| n as i32),
+                    max: max.map(|n| n as i32),
+                })
+            },
+        }
+    }
+    /// Why the output does not satisfy it, if it does not.
+    pub fn check(&self, output: &Output) -> Option<String> {
+        match *self {
+            Matcher::Equals { stream, ref value } => {
+                let actual = stream.of(output);
+                if actual == value { None } else { Some(line_diff(value, actual)) }
+            },
+            Matcher::Regex { stream, ref pattern } => {
+                if pattern.0.is_match(stream.of(output)) {
+                    None
+                } else {
+                    Some("no match".to_string())
+                }
+            },
+            Matcher::Contains { stream, ref value } => {
+                if stream.of(output).contains(value.as_str()) {
+                    None
+                } else {
+                    Some("not found".to_string())
+                }
+            },
+            Matcher::NotContains { stream, ref value } => {
+                let actual = stream.of(output);
+                actual.lines()
+                      .position(|l| l.contains(value.as_str()))
+                      .map(|i| format!("found at line {}", i + 1))
+                      // could be across lines
+                      .or(if actual.contains(value.as_str()) {
+                          Some("found".to_string())
+                      } else {
+                          None
+                      })
+            },
+            Matcher::JsonPath { stream, ref path, ref value } => {
+                let actual: Value = match serde_json::from_str(stream.of(output)) {
+                    Ok(v) => v,
+                    Err(e) => return Some(format!("not in JSON: {}", e)),
+                };
+                let expected: Value = serde_json::from_str(value).unwrap();
+                match lookup_json(&actual, path) {
+                    Some(v) if *v == expected => None,
+                    Some(v) => Some(format!("got {}", serde_json::to_string(v).unwrap())),
+                    None => Some("no such path".to_string()),
+                }
+            },
+            Matcher::Lines { stream, min, max } => {
+                let n = stream.of(output).lines().count();
+                if in_range(n, &min, &max) { None } else { Some(format!("got {} lines", n)) }
+            },
+            Matcher::StatusIn(ref set) => {
+                match output.status {
+                    Some(ref status) if set.contains(status) => None,
+                    Some(status) => Some(format!("got {}", status)),
+                    None => Some("no status".to_string()),
+                }
+            },
+            Matcher::StatusRange { min, max } => {
+                match output.status {
+                    Some(status) if in_range(status, &min, &max) => None,
+                    Some(status) => Some(format!("got {}", status)),
+                    None => Some("no status".to_string()),
+                }
+            },
+        }
+    }
+}
+
+/// The first matcher the output fails, if any.
+pub fn check_all(matchers: &[Matcher], output: &Output) -> Option<MatchFailure> {
+    for matcher in matchers.iter() {
+        if let Some(detail) = matcher.check(output) {
+            return Some(MatchFailure {
+                matcher: matcher.to_string(),
+                detail: detail,
+            });
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use toml;
+    use ::exec::Output;
+    use super::{check_all, Matcher, Stream};
+
+    fn output(stdout: &str, status: i32) -> Output {
+        Output {
+            stdout: Some(stdout.to_string()),
+            stderr: None,
+            status: Some(status),
+        }
+    }
+
+    fn parse(s: &str) -> toml::Value {
+        toml::Value::Table(toml::Parser::new(s).parse().expect("invalid toml"))
+    }
+
+    #[test]
+    fn test_stdout_matchers() {
+        let tml = parse(r#"
+            stdout = { regex = "^v\\d+", contains = ["1.2"], not_contains = "rc",
+                       lines = { min = 1, max = 2 } }
+        "#);
+        let matchers = Matcher::from_toml(Stream::Stdout, tml.lookup("stdout").unwrap()).unwrap();
+        assert_eq!(matchers.len(), 4);
+        assert!(check_all(&matchers, &output("v1.2.3\n", 0)).is_none());
+        let failure = check_all(&matchers, &output("v1.2.3-rc1\n", 0)).unwrap();
+        assert_eq!(failure.matcher, "stdout does not contain \"rc\"");
+        assert_eq!(failure.detail, "found at line 1");
+        let tml = parse("stdout = { lines = { min = -1 } }");
+        assert!(Matcher::from_toml(Stream::Stdout, tml.lookup("stdout").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_json_path() {
+        let tml = parse(r#"stdout = { json = { "$.items[1].id" = 2, "$.name" = "a" } }"#);
+        let matchers = Matcher::from_toml(Stream::Stdout, tml.lookup("stdout").unwrap()).unwrap();
+        let ok = r#"{"name":"a","items":[{"id":1},{"id":2}]}"#;
+        assert!(check_all(&matchers, &output(ok, 0)).is_none());
+        let ng = r#"{"name":"a","items":[{"id":1},{"id":3}]}"#;
+        assert_eq!(check_all(&matchers, &output(ng, 0)).unwrap().detail, "got 3");
+        let tml = parse(r#"stdout = { json = { "$.ratio" = 1.0 } }"#);
+        let matchers = Matcher::from_toml(Stream::Stdout, tml.lookup("stdout").unwrap()).unwrap();
+        assert!(check_all(&matchers, &output(r#"{"ratio":1.0}"#, 0)).is_none());
+    }
+
+    #[test]
+    fn test_status_and_equality() {
+        let set = Matcher::status_from_toml(&toml::Value::Array(vec![
+            toml::Value::Integer(0), toml::Value::Integer(2)])).unwrap();
+        assert!(set.check(&output("", 2)).is_none());
+        assert!(set.check(&output("", 1)).is_some());
+        let range = Matcher::status_from_toml(&parse("min = 1\nmax = 3")).unwrap();
+        assert!(range.check(&output("", 3)).is_none());
+        assert!(range.check(&output("", 0)).is_some());
+        let equals = Matcher::Equals { stream: Stream::Stdout, value: "a\nb".to_string() };
+        assert_eq!(equals.check(&output("a\nc", 0)).unwrap(), " a\n-b\n+c");
+    }
+}
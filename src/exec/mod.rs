@@ -1,9 +1,13 @@
+use serde_json;
 use std::fmt;
 use ::flota::Cypherable;
 
+pub mod matcher;
 pub mod session;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+use self::matcher::MatchFailure;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Output {
     pub stdout: Option<String>,
     pub stderr: Option<String>,
@@ -42,41 +46,32 @@ impl fmt::Display for Output {
     }
 }
 
-impl Output {
-    pub fn satisfy(&self, expected: &Output) -> bool {
-        if expected.stdout.is_some() && self.stdout != expected.stdout {
-            return false
-        }
-        if expected.stderr.is_some() && self.stderr != expected.stderr {
-            return false
-        }
-        if expected.status.is_some() && self.status != expected.status {
-            return false
-        }
-        true
-    }
-}
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExecResult {
     pub host: String,
     pub command: String,
     pub expected: Output,
     pub result: Output,
     pub passed: bool,
+    /// First matcher the result failed.
+    #[serde(default)]
+    pub failure: Option<MatchFailure>,
+    /// Not run at all as an earlier exec aborted.
+    #[serde(default)]
+    pub skipped: bool,
 }
 
 impl Cypherable for ExecResult {
-    fn cypher_ident(&self) -> String {
-        format!("ExecResult {{ host: '{host}',
-                               command: '{command}',
-                               expected: '{expected:?}',
-                               result: '{result:?}',
-                               passed: '{passed}' }}",
-                host = self.host,
-                command = self.command,
-                expected = self.expected,
-                result = self.result,
-                passed = self.passed)
+    fn cypher_props(&self) -> Vec<(&'static str, String)> {
+        // outputs are in JSON so that they can be read back as they were.
+        vec![
+            ("host", self.host.clone()),
+            ("command", self.command.clone()),
+            ("expected", serde_json::to_string(&self.expected).unwrap()),
+            ("result", serde_json::to_string(&self.result).unwrap()),
+            ("passed", self.passed.to_string()),
+            ("failure", serde_json::to_string(&self.failure).unwrap()),
+            ("skipped", self.skipped.to_string()),
+        ]
     }
 }
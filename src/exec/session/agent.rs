@@ -0,0 +1,229 @@
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use serde_json;
+use serde_json::builder::ObjectBuilder;
+use serde_json::Value;
+use std::any::Any;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use ::exec::Output;
+use ::exec::session::{shell_quote, ExecOptions, NullSink, OutputSink, SeedType, Session,
+                      SessionSeed};
+use ::util::errors::*;
+use ::virt::conn::Conn;
+use ::virt::domain::Domain;
+
+// Seconds to wait for each single agent command.
+const AGENT_TIMEOUT: i32 = 10;
+// Bytes in each guest-file-read/write, kept well below the agent's limit
+// on the size of a message.
+const FILE_CHUNK: usize = 48 * 1024;
+
+// Runs commands through qemu-guest-agent, which needs neither network
+// nor credentials inside the guest. Commands run as root.
+pub struct SessAgent {
+    domain: Domain,
+    // the domain must not outlive it.
+    #[allow(dead_code)]
+    conn: Conn,
+}
+
+impl SessAgent {
+    pub fn new(uri: &str, domain: &str) -> Result<Box<Self>> {
+        let conn = try!(Conn::open(uri));
+        let domain = match Domain::find(domain, &conn) {
+            Some(d) => d,
+            None => return Err(format!("no such domain: {}", domain).into()),
+        };
+        let sess = SessAgent {
+            domain: domain,
+            conn: conn,
+        };
+        // make sure someone is there to answer.
+        try!(sess.command("guest-ping", ObjectBuilder::new()));
+        Ok(Box::new(sess))
+    }
+    // "return" of the response to the command.
+    fn command(&self, execute: &str, arguments: ObjectBuilder) -> Result<Value> {
+        let cmd = ObjectBuilder::new()
+                      .insert("execute", execute)
+                      .insert("arguments", arguments.build())
+                      .build();
+        let resp = try!(self.domain.agent_command(&try!(serde_json::to_string(&cmd)),
+                                                  AGENT_TIMEOUT));
+        let resp: Value = try!(serde_json::from_str(&resp));
+        match resp.find("return") {
+            Some(ret) => Ok(ret.clone()),
+            None => Err(format!("{} failed: {}", execute, resp).into()),
+        }
+    }
+    // Kill what guest-exec left running, children of the shell first.
+    // Best effort: we are already failing on a timeout.
+    fn kill(&self, pid: i64) {
+        let kill = format!("pkill -KILL -P {0}; kill -KILL {0}", pid);
+        let args = ObjectBuilder::new()
+                       .insert("path", "/bin/sh")
+                       .insert("arg", vec!["-c", &kill]);
+        if let Err(e) = self.command("guest-exec", args) {
+            warn!("failed to kill {} on the guest: {}", pid, e);
+        }
+    }
+    fn decode(value: Option<&Value>) -> Result<String> {
+        match value.and_then(|v| v.as_str()) {
+            Some(data) => {
+                match data.from_base64() {
+                    Ok(bytes) => Ok(String::from_utf8_lossy(&bytes).into_owned()),
+                    Err(e) => Err(format!("invalid output from guest agent: {}", e).into()),
+                }
+            },
+            None => Ok(String::new()),
+        }
+    }
+    fn read_all(&self, handle: &Value, content: &mut Vec<u8>) -> Result<()> {
+        loop {
+            let read = try!(self.command("guest-file-read",
+                                         ObjectBuilder::new()
+                                             .insert("handle", handle)
+                                             .insert("count", FILE_CHUNK)));
+            if let Some(data) = read.find("buf-b64").and_then(|b| b.as_str()) {
+                match data.from_base64() {
+                    Ok(bytes) => content.extend(bytes),
+                    Err(e) => return Err(format!("invalid data from guest agent: {}", e).into()),
+                }
+            }
+            if read.find("eof").and_then(|e| e.as_bool()) != Some(false) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Session for SessAgent {
+    fn exec(&self, command: &str) -> Result<Output> {
+        self.exec_streamed(command, &ExecOptions::default(), &mut NullSink)
+    }
+    fn is_alive(&self) -> bool {
+        self.command("guest-ping", ObjectBuilder::new()).is_ok()
+    }
+    // guest-exec hands output over all at once when the command is done.
+    fn exec_streamed(&self, command: &str, opts: &ExecOptions, sink: &mut OutputSink)
+                     -> Result<Output> {
+        let command = opts.wrap(command);
+        debug!("agent command: {}", command);
+        let mut args = ObjectBuilder::new()
+                           .insert("path", "/bin/sh")
+                           .insert("arg", vec!["-c", &command])
+                           .insert("capture-output", true);
+        if let Some(stdin) = try!(opts.stdin()) {
+            args = args.insert("input-data", stdin.to_base64(STANDARD));
+        }
+        let pid = match try!(self.command("guest-exec", args)).find("pid").and_then(|p| p.as_i64()) {
+            Some(pid) => pid,
+            None => return Err("guest-exec returned no pid".into()),
+        };
+        // no deadline unless specified.
+        let deadline = match opts.timeout {
+            Some(secs) if secs > 0 => Some(Instant::now() + Duration::from_secs(secs as u64)),
+            _ => None,
+        };
+        loop {
+            let status = try!(self.command("guest-exec-status",
+                                           ObjectBuilder::new().insert("pid", pid)));
+            if status.find("exited").and_then(|e| e.as_bool()) == Some(true) {
+                let stdout = try!(Self::decode(status.find("out-data")));
+                let stderr = try!(Self::decode(status.find("err-data")));
+                sink.stdout(&stdout);
+                sink.stderr(&stderr);
+                return Ok(Output {
+                    stdout: Some(stdout),
+                    stderr: Some(stderr),
+                    // none if killed by a signal
+                    status: status.find("exitcode").and_then(|c| c.as_i64()).map(|c| c as i32),
+                });
+            }
+            if deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+                self.kill(pid);
+                return Err(format!("timed out after {}s: {}", opts.timeout.unwrap(), command)
+                               .into());
+            }
+            thread::sleep(Duration::from_millis(200));
+        }
+    }
+    fn put(&self, src: &Path, dst: &str, mode: Option<i32>) -> Result<()> {
+        debug!("agent put: {} -> {}", src.display(), dst);
+        let mut content = Vec::new();
+        try!(try!(File::open(src)).read_to_end(&mut content));
+        let handle = try!(self.command("guest-file-open",
+                                       ObjectBuilder::new()
+                                           .insert("path", dst)
+                                           .insert("mode", "w")));
+        let mut ret = Ok(());
+        for chunk in content.chunks(FILE_CHUNK) {
+            ret = self.command("guest-file-write",
+                               ObjectBuilder::new()
+                                   .insert("handle", &handle)
+                                   .insert("buf-b64", chunk.to_base64(STANDARD)))
+                      .map(|_| ());
+            if ret.is_err() {
+                break;
+            }
+        }
+        try!(self.command("guest-file-close", ObjectBuilder::new().insert("handle", &handle)));
+        try!(ret);
+        if let Some(mode) = mode {
+            let output = try!(self.exec(&format!("chmod {:o} {}", mode, shell_quote(dst))));
+            if output.status != Some(0) {
+                return Err(format!("failed to chmod {}: {:?}", dst, output.stderr).into());
+            }
+        }
+        Ok(())
+    }
+    fn get(&self, src: &str, dst: &Path) -> Result<()> {
+        debug!("agent get: {} -> {}", src, dst.display());
+        let handle = try!(self.command("guest-file-open",
+                                       ObjectBuilder::new()
+                                           .insert("path", src)
+                                           .insert("mode", "r")));
+        let mut content = Vec::new();
+        let ret = self.read_all(&handle, &mut content);
+        try!(self.command("guest-file-close", ObjectBuilder::new().insert("handle", &handle)));
+        try!(ret);
+        try!(try!(File::create(dst)).write_all(&content));
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SessSeedAgent {
+    pub uri: String,
+    pub domain: Option<String>,
+}
+
+impl SessSeedAgent {
+    pub fn new(uri: &str) -> Box<SessionSeed> {
+        Box::new(SessSeedAgent {
+            uri: uri.to_owned(),
+            domain: None,
+        })
+    }
+    pub fn override_domain(&mut self, domain: &str) -> () {
+        self.domain = Some(domain.to_owned());
+    }
+}
+
+impl SessionSeed for SessSeedAgent {
+    fn spawn(&self) -> Result<Box<Session>> {
+        match self.domain {
+            Some(ref domain) => Ok(try!(SessAgent::new(&self.uri, domain))),
+            None => Err("domain of the guest agent is not known yet".into()),
+        }
+    }
+    fn seed_type(&self) -> SeedType {
+        SeedType::Agent
+    }
+    fn as_mut_any(&mut self) -> &mut Any {
+        self
+    }
+}
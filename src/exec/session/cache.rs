@@ -0,0 +1,59 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use ::exec::session::{SeedType, Session, SessionSeeds};
+use ::util::errors::*;
+
+// Sessions spawned from the seeds, kept for reuse as long as they are
+// alive, so that each exec does not pay for e.g. a new ssh handshake.
+pub struct SessionCache {
+    seeds: SessionSeeds,
+    sessions: RefCell<Vec<(SeedType, Rc<Box<Session>>)>>,
+}
+
+impl SessionCache {
+    pub fn new(seeds: SessionSeeds) -> Self {
+        SessionCache {
+            seeds: seeds,
+            sessions: RefCell::new(Vec::new()),
+        }
+    }
+    pub fn provides(&self, seed_type: SeedType) -> bool {
+        self.seeds.iter().any(|s| s.seed_type() == seed_type)
+    }
+    /// Cached session of the type, or a new one in place of it if there
+    /// is none or it has died, e.g. as the guest has rebooted.
+    pub fn get(&self, seed_type: SeedType) -> Result<Rc<Box<Session>>> {
+        let cached = self.sessions
+                         .borrow()
+                         .iter()
+                         .find(|&&(t, _)| t == seed_type)
+                         .map(|&(_, ref sess)| sess.clone());
+        if let Some(sess) = cached {
+            if sess.is_alive() {
+                return Ok(sess);
+            }
+            debug!("{:?} session has died, reconnecting", seed_type);
+            self.sessions.borrow_mut().retain(|&(t, _)| t != seed_type);
+        }
+        let sess = match self.seeds.iter().find(|s| s.seed_type() == seed_type) {
+            Some(seed) => Rc::new(try!(seed.spawn())),
+            None => return Err(format!("no {:?} session is provided", seed_type).into()),
+        };
+        self.sessions.borrow_mut().push((seed_type, sess.clone()));
+        Ok(sess)
+    }
+    /// Forget all the sessions, e.g. after networking has been restarted.
+    pub fn invalidate(&self) {
+        self.sessions.borrow_mut().clear();
+    }
+}
+
+impl fmt::Debug for SessionCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SessionCache")
+         .field("seeds", &self.seeds)
+         .field("sessions", &self.sessions.borrow().iter().map(|&(t, _)| t).collect::<Vec<_>>())
+         .finish()
+    }
+}
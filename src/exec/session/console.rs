@@ -1,16 +1,241 @@
 use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::thread;
+use std::time::{Duration, Instant};
 use ::exec::Output;
-use ::exec::session::{SeedType, Session, SessionSeed};
+use ::exec::session::{ExecOptions, OutputSink, SeedType, Session, SessionSeed};
 use ::util::errors::*;
+use ::virt::conn::Conn;
+use ::virt::domain::Domain;
+use ::virt::stream::Stream;
+
+// Quoted in halves wherever sent, so that an echo of what we send never
+// looks like what we wait for.
+const PROMPT: &'static str = "__flota_prompt__# ";
+const END: &'static str = "__flota_end_";
+
+// Where the console is written to and read from.
+trait ConsoleIo {
+    fn send(&self, data: &[u8]) -> Result<usize>;
+    // None if nothing has arrived yet.
+    fn recv(&self, buf: &mut [u8]) -> Result<Option<usize>>;
+}
+
+struct DomainConsole {
+    stream: Stream,
+    // the stream must not outlive these.
+    #[allow(dead_code)]
+    domain: Domain,
+    #[allow(dead_code)]
+    conn: Conn,
+}
+
+impl ConsoleIo for DomainConsole {
+    fn send(&self, data: &[u8]) -> Result<usize> {
+        self.stream.send(data)
+    }
+    fn recv(&self, buf: &mut [u8]) -> Result<Option<usize>> {
+        self.stream.recv(buf)
+    }
+}
+
+// Shell on the serial console of the domain, which is there even if the
+// network is not. Output is all in stdout as the tty has no separate
+// stderr, and stdin is fed by the shell.
+pub struct SessConsole {
+    io: Box<ConsoleIo>,
+    // whatever has arrived but not been consumed yet.
+    buf: RefCell<Vec<u8>>,
+    seq: Cell<u32>,
+}
+
+fn find(hay: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.len() > hay.len() {
+        return None;
+    }
+    hay.windows(needle.len()).position(|w| w == needle)
+}
+
+impl SessConsole {
+    pub fn new(uri: &str, domain: &str, user: &str, password: &str) -> Result<Box<Self>> {
+        let conn = try!(Conn::open(uri));
+        let domain = match Domain::find(domain, &conn) {
+            Some(d) => d,
+            None => return Err(format!("no such domain: {}", domain).into()),
+        };
+        let stream = try!(Stream::new(&conn));
+        try!(domain.open_console(&stream));
+        Self::with_io(Box::new(DomainConsole {
+                          stream: stream,
+                          domain: domain,
+                          conn: conn,
+                      }),
+                      user,
+                      password)
+    }
+    fn with_io(io: Box<ConsoleIo>, user: &str, password: &str) -> Result<Box<Self>> {
+        let sess = SessConsole {
+            io: io,
+            buf: RefCell::new(Vec::new()),
+            seq: Cell::new(0),
+        };
+        try!(sess.login(user, password));
+        Ok(Box::new(sess))
+    }
+    fn send(&self, data: &str) -> Result<()> {
+        let mut rest = data.as_bytes();
+        while !rest.is_empty() {
+            let n = try!(self.io.send(rest));
+            if n == 0 {
+                thread::sleep(Duration::from_millis(10));
+            }
+            rest = &rest[n..];
+        }
+        Ok(())
+    }
+    // Wait until one of the patterns arrives, and consume the input up to
+    // the end of it. Returns which pattern and what preceded it.
+    fn expect(&self, patterns: &[&str], deadline: Instant) -> Result<(usize, Vec<u8>)> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            {
+                let mut buf = self.buf.borrow_mut();
+                let found = patterns.iter()
+                                    .enumerate()
+                                    .filter_map(|(i, p)| find(&buf, p.as_bytes()).map(|at| (at, i)))
+                                    .min();
+                if let Some((at, i)) = found {
+                    let consumed = buf.drain(..at + patterns[i].len()).collect::<Vec<u8>>();
+                    return Ok((i, consumed[..at].to_vec()));
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(format!("timed out waiting for {:?} on console", patterns).into());
+            }
+            match try!(self.io.recv(&mut chunk)) {
+                Some(0) => return Err("console closed".into()),
+                Some(n) => self.buf.borrow_mut().extend_from_slice(&chunk[..n]),
+                None => thread::sleep(Duration::from_millis(50)),
+            }
+        }
+    }
+    fn login(&self, user: &str, password: &str) -> Result<()> {
+        let deadline = Instant::now() + Duration::from_secs(60);
+        try!(self.send("\n"));
+        // someone might have left it logged in.
+        if try!(self.expect(&["login: ", "$ ", "# "], deadline)).0 == 0 {
+            try!(self.send(&format!("{}\n", user)));
+            try!(self.expect(&["assword:"], deadline));
+            try!(self.send(&format!("{}\n", password)));
+            try!(self.expect(&["$ ", "# "], deadline));
+        }
+        // no PS2 either, which heredocs would otherwise be prompted with.
+        try!(self.send("stty -echo; unset PROMPT_COMMAND; PS2=''; PS1='__flota_''prompt__# '\n"));
+        try!(self.expect(&[PROMPT], deadline));
+        Ok(())
+    }
+}
+
+impl Drop for SessConsole {
+    fn drop(&mut self) {
+        let _ = self.send("exit\n");
+    }
+}
+
+impl Session for SessConsole {
+    fn exec(&self, command: &str) -> Result<Output> {
+        self.exec_with(command, &ExecOptions::default())
+    }
+    // the shell prompts again for an empty line, unless e.g. the guest has
+    // rebooted and the login prompt is there instead.
+    fn is_alive(&self) -> bool {
+        self.buf.borrow_mut().clear();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        self.send("\n").and_then(|_| self.expect(&[PROMPT], deadline)).is_ok()
+    }
+    fn exec_streamed(&self,
+                     command: &str,
+                     opts: &ExecOptions,
+                     sink: &mut OutputSink)
+                     -> Result<Output> {
+        let output = try!(self.exec_with(command, opts));
+        if let Some(ref stdout) = output.stdout {
+            sink.stdout(stdout);
+        }
+        Ok(output)
+    }
+}
+
+impl SessConsole {
+    fn exec_with(&self, command: &str, opts: &ExecOptions) -> Result<Output> {
+        let command = try!(opts.wrap_with_stdin(command));
+        debug!("console command: {}", command);
+        let seq = self.seq.get() + 1;
+        self.seq.set(seq);
+        // forget whatever has been left over, e.g. kernel messages.
+        self.buf.borrow_mut().clear();
+        // all in one compound command, which the shell reads through before
+        // running any of it, so that neither the command eats the end marker
+        // off the tty nor the shell prompts in between. the brace goes on its
+        // own line lest it end up in a comment or a heredoc.
+        try!(self.send(&format!("{{ {}\n}} </dev/null; echo \"{}\"\"{}__ $?\"\n",
+                                command, END, seq)));
+        let deadline = Instant::now() + Duration::from_secs(opts.timeout.unwrap_or(30) as u64);
+        let marker = format!("{}{}__ ", END, seq);
+        let ret = self.expect(&[marker.as_str()], deadline)
+                      .and_then(|(_, stdout)| {
+                          self.expect(&["\n"], deadline).map(|(_, status)| (stdout, status))
+                      })
+                      .and_then(|outputs| self.expect(&[PROMPT], deadline).map(|_| outputs));
+        let (stdout, status) = match ret {
+            Ok(outputs) => outputs,
+            Err(e) => {
+                // interrupt it, lest the shell stay busy with it.
+                let _ = self.send("\x03");
+                let _ = self.expect(&[PROMPT], Instant::now() + Duration::from_secs(5));
+                return Err(e);
+            },
+        };
+        // the tty turns every newline into crlf.
+        let stdout = String::from_utf8_lossy(&stdout).replace(PROMPT, "").replace("\r\n", "\n");
+        Ok(Output {
+            stdout: Some(stdout),
+            stderr: None,
+            status: String::from_utf8_lossy(&status).trim().parse::<i32>().ok(),
+        })
+    }
+}
 
 #[derive(Debug, Clone)]
-pub struct SessConsole {}
-#[derive(Debug, Clone)]
-pub struct SessSeedConsole {}
+pub struct SessSeedConsole {
+    pub uri: String,
+    pub domain: Option<String>,
+    pub user: String,
+    pub password: String,
+}
+
+impl SessSeedConsole {
+    pub fn new(uri: &str, user: &str, password: &str) -> Box<SessionSeed> {
+        Box::new(SessSeedConsole {
+            uri: uri.to_owned(),
+            domain: None,
+            user: user.to_owned(),
+            password: password.to_owned(),
+        })
+    }
+    pub fn override_domain(&mut self, domain: &str) -> () {
+        self.domain = Some(domain.to_owned());
+    }
+}
 
 impl SessionSeed for SessSeedConsole {
     fn spawn(&self) -> Result<Box<Session>> {
-        unimplemented!()
+        match self.domain {
+            Some(ref domain) => {
+                Ok(try!(SessConsole::new(&self.uri, domain, &self.user, &self.password)))
+            },
+            None => Err("domain of the console is not known yet".into()),
+        }
     }
     fn seed_type(&self) -> SeedType {
         SeedType::Console
@@ -20,9 +245,103 @@ impl SessionSeed for SessSeedConsole {
     }
 }
 
-impl Session for SessConsole {
-    #[allow(unused_variables)]
-    fn exec(&self, command: &str) -> Result<Output> {
-        unimplemented!()
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use ::exec::session::{ExecOptions, NullSink, Session};
+    use ::util::errors::*;
+    use super::{ConsoleIo, SessConsole, END, PROMPT};
+
+    // Answers line by line as a logged in shell with echo off would,
+    // i.e. prompting after each command, and staying busy with `sleep`
+    // until interrupted.
+    struct FakeShell {
+        input: RefCell<Vec<u8>>,
+        output: RefCell<Vec<u8>>,
+        prompt_set: Cell<bool>,
+        busy: Cell<bool>,
+    }
+
+    impl ConsoleIo for FakeShell {
+        fn send(&self, data: &[u8]) -> Result<usize> {
+            if data.contains(&0x03) {
+                self.input.borrow_mut().clear();
+                self.busy.set(false);
+                self.output.borrow_mut().extend_from_slice(format!("^C\r\n{}", PROMPT).as_bytes());
+                return Ok(data.len());
+            }
+            self.input.borrow_mut().extend_from_slice(data);
+            loop {
+                let line = {
+                    let mut input = self.input.borrow_mut();
+                    match input.iter().position(|&b| b == b'\n') {
+                        Some(at) => input.drain(..at + 1).collect::<Vec<u8>>(),
+                        None => break,
+                    }
+                };
+                let line = String::from_utf8_lossy(&line).into_owned();
+                let mut output = self.output.borrow_mut();
+                if self.busy.get() {
+                    continue;
+                }
+                // the command, run once the group is closed on the next line.
+                if line.starts_with("{ ") {
+                    if line.contains("sleep") {
+                        self.busy.set(true);
+                    } else if line.contains("printf") {
+                        output.extend_from_slice(b"hello\r\nworld");
+                    } else {
+                        output.extend_from_slice(b"hello\r\n");
+                    }
+                    continue;
+                }
+                if line.starts_with("} ") {
+                    let seq = line.split('"').nth(3).unwrap().trim_right_matches("__ $?");
+                    output.extend_from_slice(format!("{}{}__ 0\r\n", END, seq).as_bytes());
+                }
+                // the prompt is ours once it has been set.
+                if line.starts_with("stty") {
+                    self.prompt_set.set(true);
+                }
+                output.extend_from_slice(if self.prompt_set.get() {
+                    PROMPT.as_bytes()
+                } else {
+                    &b"# "[..]
+                });
+            }
+            Ok(data.len())
+        }
+        fn recv(&self, buf: &mut [u8]) -> Result<Option<usize>> {
+            let mut output = self.output.borrow_mut();
+            if output.is_empty() {
+                return Ok(None);
+            }
+            let n = ::std::cmp::min(buf.len(), output.len());
+            buf[..n].copy_from_slice(&output.drain(..n).collect::<Vec<u8>>());
+            Ok(Some(n))
+        }
+    }
+
+    #[test]
+    fn test_console_exec() {
+        let shell = FakeShell {
+            input: RefCell::new(Vec::new()),
+            output: RefCell::new(Vec::new()),
+            prompt_set: Cell::new(false),
+            busy: Cell::new(false),
+        };
+        let sess = SessConsole::with_io(Box::new(shell), "root", "root").unwrap();
+        let output = sess.exec("echo hello").unwrap();
+        assert_eq!(output.stdout, Some("hello\n".to_string()));
+        assert_eq!(output.status, Some(0));
+        let output = sess.exec("printf 'hello\\nworld'").unwrap();
+        assert_eq!(output.stdout, Some("hello\nworld".to_string()));
+        assert!(sess.is_alive());
+        // interrupted on timeout, so that the shell is usable again.
+        let mut opts = ExecOptions::default();
+        opts.timeout = Some(1);
+        assert!(sess.exec_streamed("sleep 10", &opts, &mut NullSink).is_err());
+        assert!(sess.is_alive());
+        assert_eq!(sess.exec("echo hello").unwrap().stdout, Some("hello\n".to_string()));
     }
 }
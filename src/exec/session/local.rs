@@ -1,16 +1,32 @@
+use libc;
 use std::any::Any;
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
 use ::exec::Output;
-use ::exec::session::{SeedType, Session, SessionSeed};
+use ::exec::session::{CappedOutput, ExecOptions, NullSink, OutputSink, SeedType, Session,
+                      SessionSeed};
 use ::util::errors::*;
 
+// Runs commands on the hypervisor side, where flota itself is running.
 #[derive(Debug, Clone)]
 pub struct SessLocal {}
 #[derive(Debug, Clone)]
 pub struct SessSeedLocal {}
 
+impl SessSeedLocal {
+    pub fn new() -> Box<SessionSeed> {
+        Box::new(SessSeedLocal {})
+    }
+}
+
 impl SessionSeed for SessSeedLocal {
     fn spawn(&self) -> Result<Box<Session>> {
-        unimplemented!()
+        Ok(Box::new(SessLocal {}))
     }
     fn seed_type(&self) -> SeedType {
         SeedType::Local
@@ -20,9 +36,159 @@ impl SessionSeed for SessSeedLocal {
     }
 }
 
+enum Chunk {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+    Closed,
+}
+
+// Pipes are read in their own threads so that neither of them fills up
+// and blocks the command while the other is being waited on.
+fn forward<R, F>(mut pipe: R, tx: Sender<Chunk>, wrap: F)
+    where R: Read + Send + 'static,
+          F: Fn(Vec<u8>) -> Chunk + Send + 'static
+{
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            match pipe.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if tx.send(wrap(buf[..n].to_vec())).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        let _ = tx.send(Chunk::Closed);
+    });
+}
+
+// Kill the whole process group of the child, i.e. whatever the command
+// has started as well, which would keep the pipes open otherwise.
+fn kill_group(child: &mut Child) {
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.wait();
+}
+
 impl Session for SessLocal {
-    #[allow(unused_variables)]
     fn exec(&self, command: &str) -> Result<Output> {
-        unimplemented!()
+        self.exec_streamed(command, &ExecOptions::default(), &mut NullSink)
+    }
+    fn exec_streamed(&self, command: &str, opts: &ExecOptions, sink: &mut OutputSink)
+                     -> Result<Output> {
+        let command = opts.wrap(command);
+        debug!("local command: {}", command);
+        let stdin = try!(opts.stdin());
+        let mut child = try!(Command::new("sh")
+                                 .arg("-c")
+                                 .arg(&command)
+                                 .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+                                 .stdout(Stdio::piped())
+                                 .stderr(Stdio::piped())
+                                 // its own group, to be killed as a whole.
+                                 .before_exec(|| {
+                                     if unsafe { libc::setpgid(0, 0) } < 0 {
+                                         return Err(io::Error::last_os_error());
+                                     }
+                                     Ok(())
+                                 })
+                                 .spawn());
+        if let (Some(mut pipe), Some(stdin)) = (child.stdin.take(), stdin) {
+            thread::spawn(move || {
+                let _ = pipe.write_all(&stdin);
+            });
+        }
+        let (tx, rx) = channel();
+        forward(child.stdout.take().unwrap(), tx.clone(), Chunk::Stdout);
+        forward(child.stderr.take().unwrap(), tx, Chunk::Stderr);
+
+        // no deadline unless specified.
+        let deadline = match opts.timeout {
+            Some(secs) if secs > 0 => Some(Instant::now() + Duration::from_secs(secs as u64)),
+            _ => None,
+        };
+        let mut stdout = CappedOutput::new(opts.max_output());
+        let mut stderr = CappedOutput::new(opts.max_output());
+        let mut open = 2;
+        while open > 0 {
+            let chunk = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    let left = if deadline > now { deadline - now } else { Duration::from_secs(0) };
+                    match rx.recv_timeout(left) {
+                        Ok(chunk) => chunk,
+                        Err(RecvTimeoutError::Timeout) => {
+                            kill_group(&mut child);
+                            return Err(format!("timed out after {}s: {}",
+                                               opts.timeout.unwrap(), command).into());
+                        },
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                },
+                None => {
+                    match rx.recv() {
+                        Ok(chunk) => chunk,
+                        Err(_) => break,
+                    }
+                },
+            };
+            match chunk {
+                Chunk::Stdout(data) => {
+                    sink.stdout(&String::from_utf8_lossy(&data));
+                    stdout.push(&data);
+                },
+                Chunk::Stderr(data) => {
+                    sink.stderr(&String::from_utf8_lossy(&data));
+                    stderr.push(&data);
+                },
+                Chunk::Closed => open -= 1,
+            }
+        }
+        let status = try!(child.wait());
+        Ok(Output {
+            stdout: Some(stdout.into_string()),
+            stderr: Some(stderr.into_string()),
+            // none if killed by a signal
+            status: status.code(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::path::Path;
+    use std::thread;
+    use std::time::Duration;
+    use ::exec::session::{ExecOptions, NullSink, Session};
+    use super::SessLocal;
+
+    #[test]
+    fn test_exec_local() {
+        let sess = SessLocal {};
+        let mut opts = ExecOptions::default();
+        opts.env.insert("GREETING".to_string(), "hello".to_string());
+        let output = sess.exec_streamed("echo $GREETING; echo oops >&2; exit 3",
+                                        &opts, &mut NullSink).unwrap();
+        assert_eq!(output.stdout, Some("hello\n".to_string()));
+        assert_eq!(output.stderr, Some("oops\n".to_string()));
+        assert_eq!(output.status, Some(3));
+        opts.timeout = Some(1);
+        assert!(sess.exec_streamed("sleep 5", &opts, &mut NullSink).is_err());
+        // grandchildren holding the pipes open go as well.
+        let pidfile = env::temp_dir().join(".test_exec_local_pid");
+        let command = format!("sleep 5 & echo $! > {}; wait", pidfile.display());
+        assert!(sess.exec_streamed(&command, &opts, &mut NullSink).is_err());
+        let mut pid = String::new();
+        File::open(&pidfile).unwrap().read_to_string(&mut pid).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert!(!Path::new(&format!("/proc/{}", pid.trim())).exists());
+        fs::remove_file(&pidfile).unwrap();
     }
 }
@@ -1,15 +1,233 @@
 use std::any::Any;
+use std::cmp;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use ::consts::*;
 use ::flota::config;
+use ::util::md5sum::calc_md5;
 use ::util::errors::*;
 use super::Output;
 
 pub mod ssh;
+pub mod agent;
+pub mod cache;
 pub mod console;
 pub mod local;
 
+// Receives output of a command while it is running.
+pub trait OutputSink {
+    fn stdout(&mut self, data: &str);
+    fn stderr(&mut self, data: &str);
+}
+
+// Sink which throws output away.
+pub struct NullSink;
+
+impl OutputSink for NullSink {
+    fn stdout(&mut self, _: &str) {}
+    fn stderr(&mut self, _: &str) {}
+}
+
+// How to run a command, apart from the command itself.
+#[derive(Debug, Clone, Default)]
+pub struct ExecOptions {
+    /// Seconds to wait for the command to finish, in place of
+    /// the session's own default.
+    pub timeout: Option<u32>,
+    pub env: BTreeMap<String, String>,
+    pub cwd: Option<String>,
+    /// Run as this user via sudo.
+    pub user: Option<String>,
+    pub stdin: Option<config::Stdin>,
+    /// Ask for a pseudo terminal, where the session has the notion.
+    pub pty: bool,
+    /// Bytes of each stream to be kept, in place of MAX_OUTPUT.
+    pub max_output: Option<usize>,
+}
+
+// Bytes of each stream kept unless specified.
+pub const MAX_OUTPUT: usize = 1024 * 1024;
+
+// Output kept up to the cap, the rest of which is only counted.
+pub struct CappedOutput {
+    buf: Vec<u8>,
+    cap: usize,
+    dropped: usize,
+}
+
+impl CappedOutput {
+    pub fn new(cap: usize) -> Self {
+        CappedOutput {
+            buf: Vec::new(),
+            cap: cap,
+            dropped: 0,
+        }
+    }
+    pub fn push(&mut self, data: &[u8]) {
+        let n = cmp::min(self.cap - self.buf.len(), data.len());
+        self.buf.extend_from_slice(&data[..n]);
+        self.dropped += data.len() - n;
+    }
+    /// What has been kept, followed by a marker if anything has not.
+    pub fn into_string(self) -> String {
+        let mut s = String::from_utf8_lossy(&self.buf).into_owned();
+        if self.dropped > 0 {
+            s.push_str(&format!("\n[{}: truncated {} bytes]\n", *PROGNAME, self.dropped));
+        }
+        s
+    }
+}
+
+// Single-quoted for sh.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace("'", "'\\''"))
+}
+
+impl ExecOptions {
+    pub fn from_exec(exec: &config::Exec) -> Self {
+        ExecOptions {
+            timeout: exec.timeout,
+            env: exec.env.clone(),
+            cwd: exec.cwd.clone(),
+            user: exec.user.clone(),
+            stdin: exec.stdin.clone(),
+            pty: exec.pty,
+            max_output: exec.max_output.map(|n| n as usize),
+        }
+    }
+    pub fn max_output(&self) -> usize {
+        self.max_output.unwrap_or(MAX_OUTPUT)
+    }
+    /// Shell command line which runs the command in the cwd, with the
+    /// env and as the user.
+    pub fn wrap(&self, command: &str) -> String {
+        let mut wrapped = match self.cwd {
+            Some(ref cwd) => format!("cd {} && {}", shell_quote(cwd), command),
+            None => command.to_string(),
+        };
+        if self.env.is_empty() && self.user.is_none() {
+            return wrapped;
+        }
+        wrapped = format!("sh -c {}", shell_quote(&wrapped));
+        if !self.env.is_empty() {
+            // inside sudo, which would otherwise reset them.
+            wrapped = format!("env {} {}",
+                              self.env
+                                  .iter()
+                                  .map(|(k, v)| format!("{}={}", k, shell_quote(v)))
+                                  .collect::<Vec<_>>()
+                                  .join(" "),
+                              wrapped);
+        }
+        if let Some(ref user) = self.user {
+            wrapped = format!("sudo -n -u {} -- {}", shell_quote(user), wrapped);
+        }
+        wrapped
+    }
+    /// Same as `wrap`, with stdin fed from a heredoc for sessions which
+    /// cannot feed it themselves. It goes in base64 so that it may be
+    /// anything, in short lines for the sake of a tty.
+    pub fn wrap_with_stdin(&self, command: &str) -> Result<String> {
+        let wrapped = self.wrap(command);
+        let stdin = match try!(self.stdin()) {
+            Some(stdin) => stdin,
+            None => return Ok(wrapped),
+        };
+        let encoded = stdin.to_base64(STANDARD);
+        let lines = encoded.as_bytes()
+                           .chunks(76)
+                           .map(|l| String::from_utf8_lossy(l).into_owned())
+                           .collect::<Vec<_>>();
+        // never appears in base64.
+        let eof = format!("__{}_stdin__", *PROGNAME);
+        Ok(format!("base64 -d <<'{eof}' | sh -c {cmd}\n{data}\n{eof}",
+                   eof = eof,
+                   cmd = shell_quote(&wrapped),
+                   data = lines.join("\n")))
+    }
+    /// Content to be fed to stdin, if any.
+    pub fn stdin(&self) -> Result<Option<Vec<u8>>> {
+        match self.stdin {
+            Some(config::Stdin::Inline(ref s)) => Ok(Some(s.as_bytes().to_vec())),
+            Some(config::Stdin::File(ref path)) => {
+                let mut buf = Vec::new();
+                try!(try!(File::open(path)).read_to_end(&mut buf));
+                Ok(Some(buf))
+            },
+            None => Ok(None),
+        }
+    }
+}
+
 pub trait Session {
     fn exec(&self, command: &str) -> Result<Output>;
+    /// Whether it can still be used, checked before a cached one is.
+    fn is_alive(&self) -> bool {
+        true
+    }
+    /// Put the local file at `dst` on the other side.
+    #[allow(unused_variables)]
+    fn put(&self, src: &Path, dst: &str, mode: Option<i32>) -> Result<()> {
+        Err("file transfer is not supported by this session".into())
+    }
+    /// Get the file at `src` on the other side to the local path.
+    #[allow(unused_variables)]
+    fn get(&self, src: &str, dst: &Path) -> Result<()> {
+        Err("file transfer is not supported by this session".into())
+    }
+    /// Same as exec, but passes output to the sink as it arrives.
+    /// Sessions which cannot do so pass it all at once when done, have
+    /// no timeout other than their own, and are fed stdin by the shell.
+    fn exec_streamed(&self, command: &str, opts: &ExecOptions, sink: &mut OutputSink)
+                     -> Result<Output> {
+        let output = try!(self.exec(&try!(opts.wrap_with_stdin(command))));
+        if let Some(ref stdout) = output.stdout {
+            sink.stdout(stdout);
+        }
+        if let Some(ref stderr) = output.stderr {
+            sink.stderr(stderr);
+        }
+        Ok(output)
+    }
+}
+
+/// Put the script onto the other side, run it there and remove it.
+pub fn exec_script(sess: &Session,
+                   path: &Path,
+                   args: &[String],
+                   md5: &str,
+                   opts: &ExecOptions,
+                   sink: &mut OutputSink)
+                   -> Result<Output> {
+    // never run another content than the one the results are recorded for.
+    if try!(calc_md5(path)) != md5 {
+        return Err(format!("{} has changed while being run", path.display()).into());
+    }
+    // made by mktemp so that no one else can have put anything there.
+    let made = try!(sess.exec(&format!("mktemp /tmp/.{}-script-XXXXXXXXXX", *PROGNAME)));
+    let remote = match (made.status, made.stdout.as_ref().map(|s| s.trim())) {
+        (Some(0), Some(remote)) if !remote.is_empty() => remote.to_string(),
+        _ => return Err(format!("mktemp failed: {}", made.stderr.unwrap_or(String::new())).into()),
+    };
+    // readable by whoever runs it via sudo.
+    if let Err(e) = sess.put(path, &remote, Some(0o755)) {
+        let _ = sess.exec(&format!("rm -f {}", shell_quote(&remote)));
+        return Err(e);
+    }
+    let command = Some(&remote).into_iter()
+                               .chain(args.iter())
+                               .map(|a| shell_quote(a))
+                               .collect::<Vec<_>>()
+                               .join(" ");
+    let ret = sess.exec_streamed(&command, opts, sink);
+    if let Err(e) = sess.exec(&format!("rm -f {}", shell_quote(&remote))) {
+        warn!("failed to remove {}: {}", remote, e);
+    }
+    ret
 }
 
 pub trait SessionSeed : SessionSeedBoxer + fmt::Debug {
@@ -49,9 +267,10 @@ pub fn try_spawn(seeds: &SessionSeeds, prio: Vec<SeedType>) -> Result<Box<Sessio
     Err("failed to spawn session".into())
 }
 
-#[derive(PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SeedType {
     Ssh,
+    Agent,
     Console,
     Local,
 }
@@ -59,9 +278,56 @@ pub enum SeedType {
 impl SeedType {
     pub fn from_exec_type(exec_type: &config::ExecType) -> Option<SeedType> {
         match *exec_type {
+            config::ExecType::Agent => { Some(SeedType::Agent) },
             config::ExecType::Console => { Some(SeedType::Console) },
             config::ExecType::Ssh{..} => { Some(SeedType::Ssh) },
             config::ExecType::Local => { Some(SeedType::Local) },
+            // whichever other sessions the host has.
+            config::ExecType::Upload{..} => { Some(SeedType::Ssh) },
+            config::ExecType::Download{..} => { Some(SeedType::Ssh) },
+            config::ExecType::Script{..} => { Some(SeedType::Ssh) },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ::flota::config;
+    use super::{CappedOutput, ExecOptions};
+
+    #[test]
+    fn test_wrap() {
+        let mut opts = ExecOptions::default();
+        assert_eq!(opts.wrap("ls"), "ls");
+        opts.cwd = Some("/tmp/it's".to_string());
+        assert_eq!(opts.wrap("ls"), "cd '/tmp/it'\\''s' && ls");
+        opts.cwd = Some("/tmp".to_string());
+        opts.env.insert("A".to_string(), "1 2".to_string());
+        opts.user = Some("nobody".to_string());
+        assert_eq!(opts.wrap("ls"),
+                   "sudo -n -u 'nobody' -- env A='1 2' sh -c 'cd '\\''/tmp'\\'' && ls'");
+    }
+
+    #[test]
+    fn test_wrap_with_stdin() {
+        let mut opts = ExecOptions::default();
+        assert_eq!(opts.wrap_with_stdin("cat").unwrap(), "cat");
+        opts.stdin = Some(config::Stdin::Inline("hi\n".to_string()));
+        let wrapped = opts.wrap_with_stdin("cat").unwrap();
+        assert!(wrapped.starts_with("base64 -d <<'__"));
+        assert!(wrapped.contains("_stdin__' | sh -c 'cat'\naGkK\n__"));
+    }
+
+    #[test]
+    fn test_capped_output() {
+        let mut out = CappedOutput::new(4);
+        out.push(b"ab");
+        assert_eq!(out.into_string(), "ab");
+        let mut out = CappedOutput::new(4);
+        out.push(b"abc");
+        out.push(b"defg");
+        let s = out.into_string();
+        assert!(s.starts_with("abcd\n["));
+        assert!(s.ends_with(": truncated 3 bytes]\n"));
+    }
+}
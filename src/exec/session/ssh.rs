@@ -1,107 +1,342 @@
+use libc;
 use std::any::Any;
-use std::error::Error as StdError;
+use std::cell::Cell;
 use ssh2;
-use ssh2::{CheckResult, HostKeyType, KnownHostFileKind, KnownHostKeyFormat};
-use std::env;
+use ssh2::{CheckResult, FileStat, HostKeyType, KnownHostFileKind, KnownHostKeyFormat};
+use std::fs::File;
+use std::io;
 use std::io::prelude::*;
 use std::net::TcpStream;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use ::consts::*;
 use ::exec::Output;
-use ::exec::session::{SeedType, Session, SessionSeed};
+use ::exec::session::{CappedOutput, ExecOptions, NullSink, OutputSink, SeedType, Session,
+                      SessionSeed};
+use ::flota::config::setting::HostKeyPolicy;
 use ::util::errors::*;
 use ::util::ipv4::IPv4;
 
-#[allow(dead_code)]
 pub struct SessSsh {
     session: ssh2::Session,
     tcp_stream: TcpStream,
+    // set once the session has failed to open a channel.
+    broken: Cell<bool>,
+}
+
+fn would_block(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock
+}
+
+impl SessSsh {
+    // Feed stdin and drain stdout and stderr all at once, so that none of
+    // them fills up its window and stalls the command. The session must be
+    // non-blocking, and the socket is waited on while nothing can be done.
+    fn pump(&self,
+            channel: &mut ssh2::Channel,
+            stdin: Option<Vec<u8>>,
+            opts: &ExecOptions,
+            sink: &mut OutputSink)
+            -> Result<(CappedOutput, CappedOutput)> {
+        // 30 seconds unless specified. 0 means forever.
+        let deadline = match opts.timeout.unwrap_or(30) {
+            0 => None,
+            secs => Some(Instant::now() + Duration::from_secs(secs as u64)),
+        };
+        let mut stdout = CappedOutput::new(opts.max_output());
+        let mut stderr = CappedOutput::new(opts.max_output());
+        let stdin = stdin.unwrap_or(Vec::new());
+        let mut pending = &stdin[..];
+        let mut eof_sent = false;
+        let mut open = [true, true];
+        let mut buf = [0u8; 4096];
+        while open[0] || open[1] {
+            let mut progress = false;
+            if !eof_sent {
+                if !pending.is_empty() {
+                    match channel.write(pending) {
+                        Ok(n) => {
+                            pending = &pending[n..];
+                            progress = true;
+                        },
+                        Err(ref e) if would_block(e) => {},
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                if pending.is_empty() {
+                    match channel.send_eof().map_err(io::Error::from) {
+                        Ok(()) => eof_sent = true,
+                        Err(ref e) if would_block(e) => {},
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+            for i in 0..2 {
+                if !open[i] {
+                    continue;
+                }
+                match channel.stream(i as i32).read(&mut buf) {
+                    Ok(0) => open[i] = false,
+                    Ok(n) => {
+                        // a tty turns every newline into crlf.
+                        let chunk = if opts.pty {
+                            String::from_utf8_lossy(&buf[..n]).replace("\r\n", "\n").into_bytes()
+                        } else {
+                            buf[..n].to_vec()
+                        };
+                        if i == 0 {
+                            sink.stdout(&String::from_utf8_lossy(&chunk));
+                            stdout.push(&chunk);
+                        } else {
+                            sink.stderr(&String::from_utf8_lossy(&chunk));
+                            stderr.push(&chunk);
+                        }
+                        progress = true;
+                    },
+                    Err(ref e) if would_block(e) => {},
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            // a command which keeps on printing must time out as well.
+            if deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+                return Err(format!("timed out after {}s", opts.timeout.unwrap_or(30)).into());
+            }
+            if progress {
+                continue;
+            }
+            let mut fd = libc::pollfd {
+                fd: self.tcp_stream.as_raw_fd(),
+                events: libc::POLLIN | if pending.is_empty() { 0 } else { libc::POLLOUT },
+                revents: 0,
+            };
+            // not too long, for whatever libssh2 has buffered but not
+            // handed over yet.
+            unsafe {
+                libc::poll(&mut fd, 1, 100);
+            }
+        }
+        Ok((stdout, stderr))
+    }
 }
 
 impl Session for SessSsh {
     fn exec(&self, command: &str) -> Result<Output> {
+        self.exec_streamed(command, &ExecOptions::default(), &mut NullSink)
+    }
+    // a keepalive goes out without complaint even if the guest has rebooted
+    // in the meantime, which is then found out on opening a channel.
+    fn is_alive(&self) -> bool {
+        self.session.set_timeout(5000);
+        !self.broken.get() && self.session.keepalive_send().is_ok()
+    }
+    fn exec_streamed(&self, command: &str, opts: &ExecOptions, sink: &mut OutputSink)
+                     -> Result<Output> {
+        let command = opts.wrap(command);
         debug!("command: {}", command);
-        // deadline 30 seconds.
-        self.session.set_timeout(30 * 1000);
-        match self.session.channel_session() {
-            Ok(mut channel) => {
-                channel.exec(command).unwrap();
-                let mut stdout = String::new();
-                let mut stderr = String::new();
-                loop {
-                    match (channel.read_to_string(&mut stdout),
-                           channel.stderr().read_to_string(&mut stderr)) {
-                        (Ok(_), Ok(_)) => { break },
-                        (Err(e), _) | (_, Err(e)) => {
-                            // XXX
-                            if e.description().eq("would block") {
-                                continue
-                            }
-                            return Err(e.into())
-                        }
-                    }
-                }
-                Ok(Output {
-                    stdout: Some(stdout),
-                    stderr: Some(stderr),
-                    status: channel.exit_status().ok(),
-                })
+        let stdin = try!(opts.stdin());
+        self.session.set_timeout(opts.timeout.unwrap_or(30).saturating_mul(1000));
+        let mut channel = match self.session.channel_session() {
+            Ok(channel) => channel,
+            Err(e) => {
+                // so that the next one gets a new session.
+                self.broken.set(true);
+                return Err(format!("failed to open a channel: {}: {}", e, command).into());
             },
+        };
+        if opts.pty {
+            try!(channel.request_pty("vt100", None, None));
+        }
+        try!(channel.exec(&command));
+        self.session.set_blocking(false);
+        let ret = self.pump(&mut channel, stdin, opts, sink);
+        self.session.set_blocking(true);
+        let (stdout, stderr) = match ret {
+            Ok(outputs) => outputs,
             Err(e) => {
-                Err(e.into())
-            }
+                // leave nothing behind on the session, timed out or not.
+                let _ = channel.close();
+                return Err(format!("{}: {}", e, command).into());
+            },
+        };
+        try!(channel.wait_close());
+        Ok(Output {
+            stdout: Some(stdout.into_string()),
+            stderr: Some(stderr.into_string()),
+            status: channel.exit_status().ok(),
+        })
+    }
+    fn put(&self, src: &Path, dst: &str, mode: Option<i32>) -> Result<()> {
+        debug!("put: {} -> {}", src.display(), dst);
+        let sftp = try!(self.session.sftp());
+        let mut local = try!(File::open(src));
+        {
+            let mut remote = try!(sftp.create(Path::new(dst)));
+            try!(io::copy(&mut local, &mut remote));
         }
+        if let Some(mode) = mode {
+            try!(sftp.setstat(Path::new(dst), FileStat {
+                size: None,
+                uid: None,
+                gid: None,
+                perm: Some(mode as u32),
+                atime: None,
+                mtime: None,
+            }));
+        }
+        Ok(())
+    }
+    fn get(&self, src: &str, dst: &Path) -> Result<()> {
+        debug!("get: {} -> {}", src, dst.display());
+        let sftp = try!(self.session.sftp());
+        let mut remote = try!(sftp.open(Path::new(src)));
+        let mut local = try!(File::create(dst));
+        try!(io::copy(&mut remote, &mut local));
+        Ok(())
     }
 }
 
+// How to log in, tried in the order of the fields.
+#[derive(Debug, Clone)]
+pub struct SshAuth {
+    /// Keys held by ssh-agent.
+    pub agent: bool,
+    pub priv_key: PathBuf,
+    pub passphrase: Option<String>,
+    /// For a guest the key has not made it to yet.
+    pub password: Option<String>,
+}
+
+// Ours, apart from that of whoever runs the programme.
+pub fn known_hosts_file() -> PathBuf {
+    DATA_DIR.join("known_hosts")
+}
+
+/// Remove the host keys recorded for hosts of the template, which are
+/// all stale once its image has been rebuilt or deleted.
+pub fn forget_host_keys(template: &str) -> Result<()> {
+    let file = known_hosts_file();
+    if !file.exists() {
+        return Ok(());
+    }
+    let mut content = String::new();
+    try!(try!(File::open(&file)).read_to_string(&mut content));
+    // each is "<name> <key type> <key> <comment>", with the template
+    // name recorded as comment.
+    let kept = content.lines()
+                      .filter(|line| line.split_whitespace().nth(3) != Some(template))
+                      .map(|line| format!("{}\n", line))
+                      .collect::<String>();
+    if kept.len() != content.len() {
+        info!("forgetting host keys of {}", template);
+        try!(try!(File::create(&file)).write_all(kept.as_bytes()));
+    }
+    Ok(())
+}
+
 impl SessSsh {
-    pub fn new(user: &str, ip: &IPv4, port: i32, priv_key: &Path) -> Result<Box<Self>> {
+    /// Host keys are checked and recorded under `name`, along with the
+    /// template the host is of.
+    pub fn new(user: &str,
+               ip: &IPv4,
+               name: &str,
+               template: &str,
+               port: i32,
+               auth: &SshAuth,
+               policy: &HostKeyPolicy)
+               -> Result<Box<Self>> {
         debug!("tcp stream connect: {}:{}", &ip.ip(), port);
         let tcp = try!(TcpStream::connect(format!("{}:{}",
                                           &ip.ip(), port).as_str()));
-        let mut sess = ssh2::Session::new().unwrap();
-        sess.handshake(&tcp).unwrap();
-        sess.userauth_pubkey_file(user, None, priv_key, None).unwrap();
+        let mut sess = match ssh2::Session::new() {
+            Some(sess) => sess,
+            None => return Err("failed to initialize ssh session".into()),
+        };
+        try!(sess.handshake(&tcp));
+        // before anything, let alone a password, is sent to it.
+        try!(Self::check_host_key(&sess, name, template, policy));
+        try!(Self::authenticate(&sess, user, auth));
         sess.set_blocking(true);
-        sess.set_keepalive(false, 5);
+        // sent on is_alive, as libssh2 never does it on its own.
+        sess.set_keepalive(true, 15);
         sess.set_allow_sigpipe(true);
         debug!("new session: {{user: {}, host: {}, priv_key: {}}}",
-               user, ip.ip(), priv_key.to_str().unwrap());
+               user, ip.ip(), auth.priv_key.display());
         Ok(Box::new(SessSsh {
             session: sess,
             tcp_stream: tcp,
+            broken: Cell::new(false),
         }))
     }
 
-    // Update $HOME/.ssh/known_hosts file on host side (where the entire
-    // programme is running).
-    pub fn update_known_host(&self, host: &str) -> Result<()> {
-        let mut known_hosts = try!(self.session.known_hosts());
-        let file = Path::new(&env::var("HOME").unwrap()).join(".ssh/known_hosts");
-        info!("updateing {}", file.to_str().unwrap());
-        try!(known_hosts.read_file(&file, KnownHostFileKind::OpenSSH));
-        let (key, key_type) = self.session.host_key().unwrap();
+    fn authenticate(sess: &ssh2::Session, user: &str, auth: &SshAuth) -> Result<()> {
+        let mut failures = Vec::new();
+        if auth.agent {
+            match sess.userauth_agent(user) {
+                Ok(()) => return Ok(()),
+                Err(e) => failures.push(format!("agent: {}", e)),
+            }
+        }
+        match sess.userauth_pubkey_file(user,
+                                        None,
+                                        &auth.priv_key,
+                                        auth.passphrase.as_ref().map(|p| p.as_str())) {
+            Ok(()) => return Ok(()),
+            Err(e) => failures.push(format!("{}: {}", auth.priv_key.display(), e)),
+        }
+        if let Some(ref password) = auth.password {
+            match sess.userauth_password(user, password) {
+                Ok(()) => {
+                    info!("logged in as {} with password", user);
+                    return Ok(());
+                },
+                Err(e) => failures.push(format!("password: {}", e)),
+            }
+        }
+        Err(format!("failed to authenticate as {} ({})", user, failures.join(", ")).into())
+    }
+
+    // Check the key of the host against our known_hosts, recording it
+    // there if the policy says so.
+    fn check_host_key(sess: &ssh2::Session,
+                      host: &str,
+                      template: &str,
+                      policy: &HostKeyPolicy)
+                      -> Result<()> {
+        if *policy == HostKeyPolicy::Ignore {
+            return Ok(());
+        }
+        let mut known_hosts = try!(sess.known_hosts());
+        let file = known_hosts_file();
+        if file.exists() {
+            try!(known_hosts.read_file(&file, KnownHostFileKind::OpenSSH));
+        }
+        let (key, key_type) = match sess.host_key() {
+            Some(key) => key,
+            None => return Err(format!("no host key offered by {}", host).into()),
+        };
         match known_hosts.check(host, key) {
             CheckResult::Match => return Ok(()),
-            CheckResult::NotFound => {}
+            CheckResult::NotFound if *policy == HostKeyPolicy::Record => {},
+            CheckResult::NotFound => {
+                return Err(format!("host key of {} is not known in {}", host, file.display())
+                               .into());
+            },
             CheckResult::Mismatch => {
-                for r in known_hosts.iter().filter(|h| match h {
-                    &Ok(ref h) => h.name() == Some(host),
-                    _ => false,
-                }) {
-                    try!(known_hosts.remove(r.unwrap()));
-                }
-            }
-            CheckResult::Failure => panic!("failed to check the known hosts"),
+                return Err(format!("host key of {} differs from the one in {}",
+                                   host, file.display()).into());
+            },
+            CheckResult::Failure => return Err("failed to check the known hosts".into()),
         }
-        info!("adding {} to the known hosts", host);
-        try!(known_hosts.add(host,
-                             key,
-                             host,
-                             match key_type {
-                                 HostKeyType::Rsa => KnownHostKeyFormat::SshRsa,
-                                 HostKeyType::Dss => KnownHostKeyFormat::SshDss,
-                                 HostKeyType::Unknown => panic!("unknown type of key"),
-                             }));
+        info!("adding {} to {}", host, file.display());
+        let format = match key_type {
+            HostKeyType::Rsa => KnownHostKeyFormat::SshRsa,
+            HostKeyType::Dss => KnownHostKeyFormat::SshDss,
+            HostKeyType::Unknown => {
+                return Err(format!("unknown type of host key of {}", host).into());
+            },
+        };
+        try!(known_hosts.add(host, key, template, format));
         try!(known_hosts.write_file(&file, KnownHostFileKind::OpenSSH));
         Ok(())
     }
@@ -111,35 +346,60 @@ impl SessSsh {
 pub struct SessSeedSsh {
     pub user: String,
     pub ip: Option<IPv4>,
+    pub domain: Option<String>,
+    pub template: String,
     pub port: i32,
-    pub priv_key: PathBuf,
+    pub auth: SshAuth,
+    pub host_key_policy: HostKeyPolicy,
 }
 
 impl SessSeedSsh {
-    pub fn new(user: &str, ip: Option<&IPv4>, port: i32, priv_key: &Path) -> Box<SessionSeed> {
+    pub fn new(user: &str,
+               ip: Option<&IPv4>,
+               port: i32,
+               auth: SshAuth,
+               host_key_policy: &HostKeyPolicy,
+               template: &str)
+               -> Box<SessionSeed> {
         Box::new(SessSeedSsh {
             user: user.to_owned(),
             ip: match ip { Some(v) => Some(v.clone()), None => None },
+            domain: None,
+            template: template.to_owned(),
             port: port,
-            priv_key: priv_key.to_path_buf(),
+            auth: auth,
+            host_key_policy: host_key_policy.clone(),
         })
     }
     pub fn override_ip(&mut self, ip: &IPv4) -> () {
         self.ip = Some(ip.clone());
     }
+    /// Its host key is known by the domain name, which unlike the ip
+    /// stays the same for the host.
+    pub fn override_domain(&mut self, domain: &str) -> () {
+        self.domain = Some(domain.to_owned());
+    }
 }
 
 impl SessionSeed for SessSeedSsh {
     fn spawn(&self) -> Result<Box<Session>> {
         // at this moment self.ip must be some.
+        let ip = match self.ip {
+            Some(ref v) => v,
+            None => panic!("would not panic")
+        };
+        let name = match self.domain {
+            Some(ref domain) => domain.clone(),
+            None => ip.ip().to_string(),
+        };
         Ok(try!(
             self::SessSsh::new(&self.user,
-                               match self.ip {
-                                   Some(ref v) => v,
-                                   None => panic!("would not panic")
-                               },
+                               ip,
+                               &name,
+                               &self.template,
                                self.port,
-                               self.priv_key.as_path())
+                               &self.auth,
+                               &self.host_key_policy)
         ))
     }
     fn seed_type(&self) -> SeedType {
@@ -1,18 +1,22 @@
-use rusted_cypher::graph::GraphClient;
 use std::collections::HashSet;
 use std::sync::Arc;
 use toml;
+use ::distro::Distros;
 use ::flota::{hash, Cypherable};
+use ::flota::store::Store;
 use ::util::errors::*;
 
-use super::Exec;
+use super::{Exec, ExecType};
 use super::template::Template;
 
 pub mod host;
 use self::host::Host;
 
+pub mod schedule;
+use self::schedule::CronSchedule;
+
 pub mod watchpoint;
-use self::watchpoint::WatchPoint;
+use self::watchpoint::{WatchPoint, WatchSchedule};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Cluster {
@@ -21,6 +25,12 @@ pub struct Cluster {
     /// Watchpoints. Empty array is okay, in that case only this
     /// cluster's config change triggers test reruns.
     pub watchpoints: Vec<WatchPoint>,
+    /// When each of the watchpoints is perceived, in the same order.
+    pub watch_schedules: Vec<WatchSchedule>,
+    /// Run regardless of the watchpoints on this schedule too,
+    /// e.g. "0 3 * * *" for every night.
+    /// DEFAULT: none
+    pub schedule: Option<CronSchedule>,
     /// Hosts which belong to this cluster. Note that
     /// these are set up in the same order.
     pub hosts: Vec<Host>,
@@ -41,87 +51,37 @@ pub struct Cluster {
 }
 
 impl Cypherable for Cluster {
-    fn cypher_ident(&self) -> String {
-        format!("Cluster {{ name: '{}' }}", self.name)
+    fn cypher_props(&self) -> Vec<(&'static str, String)> {
+        vec![ ("name", self.name.clone()) ]
     }
 }
 
 impl Cluster {
-    pub fn save(&self) -> Result<()> {
-        // prepare and start transaction
-        let graph = GraphClient::connect(::NEO4J_ENDPOINT).unwrap();
-        let mut transaction = graph.cypher().transaction();
-        transaction.add_statement("MATCH (n: TRANSACTION) RETURN n");
-        let (mut transaction, _) = transaction.begin().unwrap();
-
-        // save watchpoint
-        for ref watchpoint in self.watchpoints.iter() {
-            if let Err(e) = save_child_rel!(&mut transaction, self, watchpoint, "WATCH") {
-                error!("{}", e);
-                try!(transaction.rollback());
-                return Err("failed to save Cluster".into());
-            }
-        }
-
-        // save tests
-        for ref pre_test in self.pre_tests.iter() {
-            if let Err(e) = save_child_rel!(&mut transaction, self, pre_test, "EXEC") {
-                error!("{}", e);
-                try!(transaction.rollback());
-                return Err("failed to save Cluster".into());
-            }
-        }
-        for ref test in self.tests.iter() {
-            if let Err(e) = save_child_rel!(&mut transaction, self, test, "EXEC") {
-                error!("{}", e);
-                try!(transaction.rollback());
-                return Err("failed to save Cluster".into());
-            }
-        }
-        for ref post_test in self.post_tests.iter() {
-            if let Err(e) = save_child_rel!(&mut transaction, self, post_test, "EXEC") {
-                error!("{}", e);
-                try!(transaction.rollback());
-                return Err("failed to save Cluster".into());
-            }
-        }
-
-        // save hosts
-        for ref host in self.hosts.iter() {
-            if let Err(e) = save_child_rel!(&mut transaction, self, host, "DEFINE") {
-                error!("{}", e);
-                try!(transaction.rollback());
-                return Err("failed to save Cluster".into());
-            }
-        }
-
-        // commit transaction
-        transaction.commit().map(|_| ()).map_err(|e| e.into())
+    pub fn save(&self, store: &Store) -> Result<()> {
+        store.save_cluster(self)
     }
-    pub fn is_first_run(&self) -> Result<bool> {
-        // XXX: if first and last cluster run was aborted for some reason,
-        // it will mistakenly conclude that it was ok and skip FirstRun until
-        // some watchpoint triggers it. some mechanism to notice cluster
-        // state has to be introduced.
-        let graph = GraphClient::connect(::NEO4J_ENDPOINT).unwrap();
-        graph.cypher().exec(
-            format!("MATCH (self: {})-[:EXEC]->(t: Exec)<-[:IS_RESULT_OF]-(res: ExecResult)
-                     RETURN res", self.cypher_ident()).as_ref()
-        ).map(|r| r.rows().count() == 0).map_err(|e| e.into())
+    pub fn is_first_run(&self, store: &Store) -> Result<bool> {
+        store.is_first_run(self)
     }
     fn from_toml_inner(tml: &toml::Value, templates: &HashSet<Arc<Template>>) -> Result<Cluster> {
         let name = tml.lookup("name").map(|val| val.as_str().unwrap()).unwrap();
-        let watchpoints = match tml.lookup("watchpoint") {
-            Some(&toml::Value::Array(ref tml_watchpoints)) => {
-                let mut watchpoints = Vec::new();
-                for tml_watchpoint in tml_watchpoints {
-                    watchpoints.push(
-                        WatchPoint::from_toml(tml_watchpoint).unwrap()
-                    )
+        let mut watchpoints = Vec::new();
+        let mut watch_schedules = Vec::new();
+        if let Some(&toml::Value::Array(ref tml_watchpoints)) = tml.lookup("watchpoint") {
+            for tml_watchpoint in tml_watchpoints {
+                let watchpoint = WatchPoint::from_toml(tml_watchpoint).unwrap();
+                watch_schedules.push(try!(WatchSchedule::from_toml(tml_watchpoint, &watchpoint)));
+                watchpoints.push(watchpoint);
+            }
+        }
+        let schedule = match tml.lookup("schedule") {
+            Some(val) => {
+                match val.as_str() {
+                    Some(expr) => Some(try!(CronSchedule::parse(expr))),
+                    None => return Err("`schedule` must be a string".into()),
                 }
-                watchpoints
             },
-            _ => { vec![] }
+            None => None,
         };
         let hosts = match tml.lookup("host") {
             Some(&toml::Value::Array(ref tml_hsts)) => {
@@ -172,25 +132,84 @@ impl Cluster {
         let persistent = tml.lookup("persistent")
             .map(|val| val.as_bool().unwrap())
             .unwrap_or(true);
-        Ok(Cluster {
+        let mut cluster = Cluster {
             name: name.to_owned(),
             watchpoints: watchpoints,
+            watch_schedules: watch_schedules,
+            schedule: schedule,
             hosts: hosts,
             pre_tests: pre_tests,
             tests: tests,
             post_tests: post_tests,
             destroy_when_finished: destroy_when_finished,
             persistent: persistent,
-        })
+        };
+        // scripts are watched as well, so that editing one re-runs it.
+        let scripts = cluster.execs()
+                             .into_iter()
+                             .filter_map(|e| match e.exec_type {
+                                 ExecType::Script { ref path, .. } => Some(path.clone()),
+                                 _ => None,
+                             })
+                             .collect::<Vec<_>>();
+        for path in scripts {
+            let watchpoint = WatchPoint::File { path: path };
+            if !cluster.watchpoints.contains(&watchpoint) {
+                cluster.watch_schedules.push(WatchSchedule::default_for(&watchpoint));
+                cluster.watchpoints.push(watchpoint);
+            }
+        }
+        try!(cluster.check_agent_execs());
+        Ok(cluster)
     }
-    pub fn from_toml(tml: &toml::Value, templates: &HashSet<Arc<Template>>) -> Result<Cluster> {
-        match Self::from_toml_inner(tml, templates) {
-            Ok(cluster) => {
-                cluster.save().unwrap();
-                Ok(cluster)
-            },
-            Err(e) => Err(e),
+    // agent execs are of no use on hosts whose distro has no capable
+    // agent, which would otherwise be found out only on running them.
+    fn check_agent_execs(&self) -> Result<()> {
+        let mut hosts = Vec::new();
+        for host in self.hosts.iter() {
+            if host.solo_pre_tests
+                   .iter()
+                   .chain(host.solo_tests.iter())
+                   .chain(host.solo_post_tests.iter())
+                   .any(|e| e.exec_type == ExecType::Agent) {
+                hosts.push(host);
+            }
+        }
+        for exec in self.pre_tests.iter().chain(self.tests.iter()).chain(self.post_tests.iter()) {
+            if exec.exec_type != ExecType::Agent {
+                continue;
+            }
+            if let Some(host) = self.hosts.iter().find(|h| Some(&h.hostname) == exec.host.as_ref()) {
+                hosts.push(host);
+            }
+        }
+        for host in hosts {
+            let capable = Distros::of(&host.template)
+                              .map(|d| d.has_guest_agent())
+                              .unwrap_or(false);
+            if !capable {
+                return Err(format!("agent execs are not supported on {}, as template {} \
+                                    has no guest agent", host.hostname, host.template.name)
+                               .into());
+            }
         }
+        Ok(())
+    }
+    /// Every exec of the cluster, its hosts' included.
+    pub fn execs(&self) -> Vec<&Exec> {
+        let mut execs = Vec::new();
+        for host in self.hosts.iter() {
+            execs.extend(host.solo_pre_tests.iter());
+            execs.extend(host.solo_tests.iter());
+            execs.extend(host.solo_post_tests.iter());
+        }
+        execs.extend(self.pre_tests.iter());
+        execs.extend(self.tests.iter());
+        execs.extend(self.post_tests.iter());
+        execs
+    }
+    pub fn from_toml(tml: &toml::Value, templates: &HashSet<Arc<Template>>) -> Result<Cluster> {
+        Self::from_toml_inner(tml, templates)
     }
     pub fn id(&self) -> u64 {
         hash(self)
@@ -0,0 +1,136 @@
+use time;
+use ::util::errors::*;
+
+/// When the cluster is run whether or not anything has changed, in
+/// crontab(5) syntax without names, e.g. "0 3 * * *" for every night
+/// at 3:00 in local time. As in cron, a day matching either of day of
+/// month and day of week is enough if both of them are restricted.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CronSchedule {
+    pub expr: String,
+    // bit n set if n matches.
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+// Each comma separated part is one of `*`, `n` or `a-b`, optionally
+// followed by `/step`.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let invalid = || -> Error { format!("invalid schedule field: {}", field).into() };
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(i) => {
+                match part[i + 1..].parse::<u32>() {
+                    Ok(step) if step > 0 => (&part[..i], step),
+                    _ => return Err(invalid()),
+                }
+            },
+            None => (part, 1),
+        };
+        let (from, to) = if range == "*" {
+            (min, max)
+        } else {
+            let mut bounds = range.splitn(2, '-');
+            let from = try!(bounds.next().unwrap().parse::<u32>().map_err(|_| invalid()));
+            let to = match bounds.next() {
+                Some(to) => try!(to.parse::<u32>().map_err(|_| invalid())),
+                // `n/step` runs from n to the end.
+                None if step > 1 => max,
+                None => from,
+            };
+            (from, to)
+        };
+        if from < min || to > max || from > to {
+            return Err(invalid());
+        }
+        let mut n = from;
+        while n <= to {
+            bits |= 1 << n;
+            n += step;
+        }
+    }
+    Ok(bits)
+}
+
+impl CronSchedule {
+    pub fn parse(expr: &str) -> Result<Self> {
+        let fields = expr.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(format!("schedule needs 5 fields: {}", expr).into());
+        }
+        let mut weekdays = try!(parse_field(fields[4], 0, 7));
+        // both 0 and 7 are sunday.
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(CronSchedule {
+            expr: expr.to_owned(),
+            minutes: try!(parse_field(fields[0], 0, 59)),
+            hours: try!(parse_field(fields[1], 0, 23)),
+            days: try!(parse_field(fields[2], 1, 31)),
+            months: try!(parse_field(fields[3], 1, 12)),
+            weekdays: weekdays,
+            days_restricted: !fields[2].starts_with('*'),
+            weekdays_restricted: !fields[4].starts_with('*'),
+        })
+    }
+    pub fn matches(&self, tm: &time::Tm) -> bool {
+        let is_set = |bits: u64, n: i32| bits & (1 << n) != 0;
+        let day = is_set(self.days, tm.tm_mday);
+        let weekday = is_set(self.weekdays, tm.tm_wday);
+        let day_matches = if self.days_restricted && self.weekdays_restricted {
+            day || weekday
+        } else {
+            day && weekday
+        };
+        is_set(self.minutes, tm.tm_min) && is_set(self.hours, tm.tm_hour) &&
+        is_set(self.months, tm.tm_mon + 1) && day_matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time;
+    use super::CronSchedule;
+
+    fn tm(mday: i32, wday: i32, hour: i32, min: i32) -> time::Tm {
+        time::Tm {
+            tm_sec: 0,
+            tm_min: min,
+            tm_hour: hour,
+            tm_mday: mday,
+            tm_mon: 0,
+            tm_year: 117,
+            tm_wday: wday,
+            tm_yday: mday - 1,
+            tm_isdst: 0,
+            tm_utcoff: 0,
+            tm_nsec: 0,
+        }
+    }
+
+    #[test]
+    fn test_cron_schedule() {
+        let nightly = CronSchedule::parse("0 3 * * *").unwrap();
+        assert!(nightly.matches(&tm(1, 0, 3, 0)));
+        assert!(!nightly.matches(&tm(1, 0, 3, 1)));
+        let weekdays = CronSchedule::parse("*/15 9-17 * * 1-5").unwrap();
+        assert!(weekdays.matches(&tm(2, 1, 9, 45)));
+        assert!(!weekdays.matches(&tm(2, 1, 9, 50)));
+        assert!(!weekdays.matches(&tm(1, 0, 9, 45)));
+        // either of them if both are restricted.
+        let either = CronSchedule::parse("0 0 1 * 7").unwrap();
+        assert!(either.matches(&tm(1, 3, 0, 0)));
+        assert!(either.matches(&tm(8, 0, 0, 0)));
+        assert!(!either.matches(&tm(9, 1, 0, 0)));
+        assert!(CronSchedule::parse("0 3 * *").is_err());
+        assert!(CronSchedule::parse("60 3 * * *").is_err());
+        assert!(CronSchedule::parse("*/0 3 * * *").is_err());
+    }
+}
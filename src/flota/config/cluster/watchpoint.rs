@@ -1,3 +1,7 @@
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+use glob::Pattern;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use toml;
 
@@ -5,7 +9,7 @@ use ::flota::Cypherable;
 use ::util::errors::*;
 use ::util::url::Url;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum WatchPoint {
     Git {
         uri: Url,
@@ -15,33 +19,150 @@ pub enum WatchPoint {
     },
     File {
         path: PathBuf,
+    },
+    /// Files in the directory tree, perceived as a digest over
+    /// them all. Globs are relative to `path`, and `exclude` prunes
+    /// directories as well.
+    Dir {
+        path: PathBuf,
+        /// DEFAULT: ["**"]
+        include: Vec<String>,
+        /// DEFAULT: []
+        exclude: Vec<String>,
+    },
+    /// Perceived from stdout of the command run on our side, e.g.
+    /// `rpm -q libvirt`. Perception fails unless it exits with 0.
+    Command {
+        command: String,
+        /// Seconds to wait for it, beyond which it is not perceived. At
+        /// least 1.
+        /// DEFAULT: 30
+        timeout: u32,
+    },
+    /// Perceived from ETag or Last-Modified of the response, or
+    /// digest of its body if it has neither.
+    Http {
+        url: Url,
+        /// DEFAULT: "GET"
+        method: String,
+        /// e.g. for credentials.
+        /// DEFAULT: {}
+        headers: BTreeMap<String, String>,
+    },
+}
+
+/// When a watchpoint is perceived, which is not part of what it is,
+/// lest changing it reset the history of perceptions.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct WatchSchedule {
+    /// Seconds between perceptions, doubled on each consecutive
+    /// failure up to an hour. Changes to `file` and `dir` are
+    /// noticed as they happen as well.
+    /// DEFAULT: 60, 600 (file, dir)
+    pub poll_interval: u32,
+}
+
+impl WatchSchedule {
+    pub fn default_for(watchpoint: &WatchPoint) -> Self {
+        WatchSchedule {
+            poll_interval: match *watchpoint {
+                WatchPoint::File { .. } | WatchPoint::Dir { .. } => 600,
+                _ => 60,
+            },
+        }
+    }
+    pub fn from_toml(tml: &toml::Value, watchpoint: &WatchPoint) -> Result<Self> {
+        let default = Self::default_for(watchpoint).poll_interval as i32;
+        let poll_interval = unfold!(tml, "poll_interval", i32, optional, default);
+        if poll_interval <= 0 {
+            return Err("`poll_interval` must be positive".into());
+        }
+        Ok(WatchSchedule {
+            poll_interval: poll_interval as u32,
+        })
     }
 }
 
 impl Cypherable for WatchPoint {
-    fn cypher_ident(&self) -> String {
+    fn cypher_props(&self) -> Vec<(&'static str, String)> {
         match *self {
             WatchPoint::Git { ref uri, ref remote, ref refs, ref checkout_dir } => {
-                format!("WatchPoint {{ type: 'Git',
-                                       uri: '{}',
-                                       remote: '{}',
-                                       refs: '{}',
-                                       checkout_dir: '{}' }}",
-                        uri.as_str(),
-                        remote.as_str(),
-                        refs.join(", "),
-                        checkout_dir.to_str().unwrap())
+                vec![
+                    ("type", "Git".to_string()),
+                    ("uri", uri.as_str().to_owned()),
+                    ("remote", remote.clone()),
+                    ("refs", refs.join(", ")),
+                    ("checkout_dir", checkout_dir.to_str().unwrap().to_owned()),
+                ]
             },
             WatchPoint::File { ref path } => {
-                format!("WatchPoint {{ type: 'File',
-                                       path: '{}' }}",
-                        path.to_str().unwrap())
-            }
+                vec![
+                    ("type", "File".to_string()),
+                    ("path", path.to_str().unwrap().to_owned()),
+                ]
+            },
+            WatchPoint::Dir { ref path, ref include, ref exclude } => {
+                vec![
+                    ("type", "Dir".to_string()),
+                    ("path", path.to_str().unwrap().to_owned()),
+                    ("include", include.join(", ")),
+                    ("exclude", exclude.join(", ")),
+                ]
+            },
+            WatchPoint::Command { ref command, timeout } => {
+                vec![
+                    ("type", "Command".to_string()),
+                    ("command", command.clone()),
+                    ("timeout", timeout.to_string()),
+                ]
+            },
+            WatchPoint::Http { ref url, ref method, ref headers } => {
+                // values only in a digest, as they might be credentials,
+                // which still keeps apart those differing only in them.
+                let mut hasher = Md5::new();
+                for (name, value) in headers.iter() {
+                    hasher.input_str(&format!("{}: {}\n", name, value));
+                }
+                vec![
+                    ("type", "Http".to_string()),
+                    ("url", url.as_str().to_owned()),
+                    ("method", method.clone()),
+                    ("headers", headers.keys().cloned().collect::<Vec<_>>().join(", ")),
+                    ("headers_md5", hasher.result_str()),
+                ]
+            },
         }
     }
 }
 
+fn globs(tml: &toml::Value, key: &str, default: Vec<String>) -> Result<Vec<String>> {
+    match tml.lookup(key) {
+        Some(&toml::Value::Array(ref vals)) => {
+            let mut globs = Vec::new();
+            for val in vals.iter() {
+                match val.as_str() {
+                    Some(s) if Pattern::new(s).is_ok() => globs.push(s.to_owned()),
+                    _ => return Err(format!("invalid glob in `{}`: {}", key, val).into()),
+                }
+            }
+            Ok(globs)
+        },
+        Some(_) => Err(format!("`{}` must be an array of globs", key).into()),
+        None => Ok(default),
+    }
+}
+
 impl WatchPoint {
+    /// Type name as written in the config.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            WatchPoint::Git { .. } => "git",
+            WatchPoint::File { .. } => "file",
+            WatchPoint::Dir { .. } => "dir",
+            WatchPoint::Command { .. } => "command",
+            WatchPoint::Http { .. } => "http",
+        }
+    }
     pub fn from_toml(tml: &toml::Value) -> Result<Self> {
         let ty = unfold!(tml, "type", String);
         // WatchPoint::Git
@@ -63,6 +184,46 @@ impl WatchPoint {
             Ok(WatchPoint::File {
                 path: unfold!(tml, "path", PathBuf),
             })
+        // WatchPoint::Dir
+        } else if ty == "dir" {
+            let include = try!(globs(tml, "include", vec!["**".to_string()]));
+            let exclude = try!(globs(tml, "exclude", vec![]));
+            Ok(WatchPoint::Dir {
+                path: unfold!(tml, "path", PathBuf),
+                include: include,
+                exclude: exclude,
+            })
+        // WatchPoint::Command
+        } else if ty == "command" {
+            // it would otherwise never be given up on.
+            let timeout = unfold!(tml, "timeout", i32, optional, 30);
+            if timeout < 1 {
+                return Err("`timeout` of a command watchpoint must be at least 1".into());
+            }
+            Ok(WatchPoint::Command {
+                command: unfold!(tml, "command", String),
+                timeout: timeout as u32,
+            })
+        // WatchPoint::Http
+        } else if ty == "http" {
+            let mut headers = BTreeMap::new();
+            if let Some(vals) = tml.lookup("headers").and_then(|v| v.as_table()) {
+                for (key, val) in vals.iter() {
+                    match val.as_str() {
+                        Some(val) => headers.insert(key.clone(), val.to_string()),
+                        None => return Err(format!("invalid header: {}", key).into()),
+                    };
+                }
+            }
+            let method = unfold!(tml, "method", String, optional, "GET".to_string());
+            if method != "GET" && method != "HEAD" {
+                return Err(format!("unsupported method: {}", method).into());
+            }
+            Ok(WatchPoint::Http {
+                url: unfold!(tml, "url", Url),
+                method: method,
+                headers: headers,
+            })
         } else {
             Err(format!("unsupported watchpoint type: {}", ty).into())
         }
@@ -1,12 +1,15 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::convert::AsRef;
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use toml;
+use ::exec::matcher::{Matcher, Stream};
+use ::util::md5sum::calc_md5;
 use ::util::errors::*;
-use ::flota::Cypherable;
+use ::flota::{hash, Cypherable};
+use ::flota::store::Store;
 
 macro_rules! unfold {
     ( $toml:ident, $key:expr, $ty:tt, optional, $default:expr ) => {{
@@ -61,9 +64,41 @@ macro_rules! unfold {
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 // XXX: local/remote choices might probably be sufficient
 pub enum ExecType {
+    /// Through qemu-guest-agent, as root. Only for templates whose distro
+    /// has a capable agent, see `distro::Base::has_guest_agent`, or the
+    /// config is rejected.
+    Agent,
     Console,
     Local,
     Ssh,
+    /// Put the local file onto the host, over ssh only.
+    Upload {
+        src: PathBuf,
+        dst: String,
+        /// Given as an octal string, e.g. "0644".
+        mode: Option<i32>,
+    },
+    /// Get the file on the host to the local path, over ssh only.
+    Download {
+        src: String,
+        dst: PathBuf,
+    },
+    /// Local script to be put onto the host and run there, over ssh only.
+    /// As md5 of its content is part of it, so is of the exec's
+    /// identity, results of different contents never share a history.
+    /// It is taken again at each run, and the script is watched.
+    Script {
+        path: PathBuf,
+        args: Vec<String>,
+        md5: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Stdin {
+    Inline(String),
+    /// Local file read at each run.
+    File(PathBuf),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -73,77 +108,261 @@ pub struct Exec {
     /// Hostname on which this Exec will be executed.
     pub host: Option<String>,
     /// For the time being this is supposed to be directly
-    /// executed on the guest side.
+    /// executed on the guest side. For file transfers,
+    /// just a description of it.
     pub command: String,
+    /// Environment variables the command runs with.
+    pub env: BTreeMap<String, String>,
+    /// Working directory of the command.
+    pub cwd: Option<String>,
+    /// Run as this user via sudo.
+    pub user: Option<String>,
+    /// Given either inline or as { file = "/local/path" }.
+    pub stdin: Option<Stdin>,
+    /// Run it on a pseudo terminal, for commands which behave
+    /// differently otherwise. Its stderr comes in stdout then.
+    /// DEFAULT: false
+    pub pty: bool,
+    /// Bytes of each of stdout and stderr to be kept, beyond
+    /// which the rest is dropped with a marker.
+    /// DEFAULT: 1048576
+    pub max_output: Option<u32>,
     /// Optionally you can set an expected stdout.
     pub expect_stdout: Option<String>,
     /// Optionally you can set an expected stderr.
     pub expect_stderr: Option<String>,
     /// Optionally you can set an expected exit code.
     pub expect_status: Option<i32>,
-    /// If either expect_stdout or expect_status set,
-    /// and if this is set true, all the following
-    /// executions would be skipped on an unexpecte result.
+    /// Expectations other than the exact ones above, i.e.
+    /// those given to stdout, stderr or status in table
+    /// (or for status, array) form.
+    pub expect: Vec<Matcher>,
+    /// If set true, all the following executions but those
+    /// with always_run would be skipped once this has failed,
+    /// i.e. any of its expectations is unmet, retries aside.
+    /// DEFAULT: false
     pub abort_on_failure: bool,
+    /// If set true, this would be executed even after
+    /// an abort, e.g. to clean up.
+    pub always_run: bool,
+    /// Seconds to wait for the command to finish.
+    /// 0 means forever.
+    /// DEFAULT: 30 (ssh), 0 (local)
+    pub timeout: Option<u32>,
+    /// How many times to re-run on an unexpected result.
+    /// DEFAULT: 0
+    pub retries: u32,
+    /// Seconds between re-runs.
+    /// DEFAULT: 5
+    pub retry_interval: u32,
+    /// If set, keep re-running until the expectation holds,
+    /// for up to this many seconds, e.g. to wait for some
+    /// service to converge.
+    pub wait_until: Option<u32>,
 }
 
 impl Cypherable for Exec {
-    fn cypher_ident(&self) -> String {
-        format!("Exec {{ exec_type: '{exec_type:?}',
-                         host: '{host:?}',
-                         command: '{command}',
-                         expect_stdout: '{expect_stdout:?}',
-                         expect_stderr: '{expect_stderr:?}',
-                         expect_status: '{expect_status:?}',
-                         abort_on_failure: '{abort_on_failure}' }}",
-                exec_type = self.exec_type,
-                host = self.host,
-                command = self.command,
-                expect_stdout = self.expect_stdout,
-                expect_stderr = self.expect_stderr,
-                expect_status = self.expect_status,
-                abort_on_failure = self.abort_on_failure)
+    fn cypher_props(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("exec_type", format!("{:?}", self.exec_type)),
+            ("host", format!("{:?}", self.host)),
+            ("command", self.command.clone()),
+            ("env", format!("{:?}", self.env)),
+            ("cwd", format!("{:?}", self.cwd)),
+            ("user", format!("{:?}", self.user)),
+            ("stdin", format!("{:?}", self.stdin)),
+            ("pty", self.pty.to_string()),
+            ("max_output", format!("{:?}", self.max_output)),
+            ("expect_stdout", format!("{:?}", self.expect_stdout)),
+            ("expect_stderr", format!("{:?}", self.expect_stderr)),
+            ("expect_status", format!("{:?}", self.expect_status)),
+            ("expect", format!("{:?}", self.expect)),
+            ("abort_on_failure", self.abort_on_failure.to_string()),
+            ("always_run", self.always_run.to_string()),
+            ("timeout", format!("{:?}", self.timeout)),
+            ("retries", self.retries.to_string()),
+            ("retry_interval", self.retry_interval.to_string()),
+            ("wait_until", format!("{:?}", self.wait_until)),
+        ]
+    }
+}
+
+// Counts and seconds, which are read as i32 and kept as u32.
+fn non_negative(key: &str, n: Option<i32>) -> Result<Option<u32>> {
+    match n {
+        Some(n) if n < 0 => Err(format!("`{}` must not be negative", key).into()),
+        n => Ok(n.map(|n| n as u32)),
     }
 }
 
 impl Exec {
     pub fn from_toml(tml: &toml::Value) -> Result<Exec> {
         let exec_type = unfold!(tml, "type", String);
-        let command = unfold!(tml, "command", String);
-        let expect_stdout = unfold!(tml, "stdout", String, optional);
-        let expect_stderr = unfold!(tml, "stderr", String, optional);
-        let expect_status = unfold!(tml, "status", i32, optional);
+        let mut env = BTreeMap::new();
+        if let Some(vals) = tml.lookup("env").and_then(|v| v.as_table()) {
+            for (key, val) in vals.iter() {
+                let valid = key.chars().enumerate().all(|(i, c)| {
+                    c == '_' || (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') ||
+                    (i > 0 && c.is_digit(10))
+                });
+                match val.as_str() {
+                    Some(val) if valid && !key.is_empty() => {
+                        env.insert(key.clone(), val.to_string());
+                    },
+                    _ => return Err(format!("invalid env: {}", key).into()),
+                }
+            }
+        }
+        let cwd = unfold!(tml, "cwd", String, optional);
+        let user = unfold!(tml, "user", String, optional);
+        let stdin = match tml.lookup("stdin") {
+            Some(&toml::Value::String(ref s)) => Some(Stdin::Inline(s.clone())),
+            Some(val) => {
+                match val.lookup("file").and_then(|v| v.as_str()) {
+                    Some(path) => Some(Stdin::File(PathBuf::from(path))),
+                    None => return Err("`stdin` must be a string or { file }".into()),
+                }
+            },
+            None => None,
+        };
+        let pty = unfold!(tml, "pty", bool, optional, false);
+        let max_output = try!(non_negative("max_output", unfold!(tml, "max_output", i32, optional)));
+        let expect_stdout = tml.lookup("stdout").and_then(|v| v.as_str()).map(|s| s.to_string());
+        let expect_stderr = tml.lookup("stderr").and_then(|v| v.as_str()).map(|s| s.to_string());
+        let expect_status = tml.lookup("status").and_then(|v| v.as_integer()).map(|n| n as i32);
+        let mut expect = Vec::new();
+        for &(key, stream) in [("stdout", Stream::Stdout), ("stderr", Stream::Stderr)].iter() {
+            if let Some(val) = tml.lookup(key).and_then(|v| v.as_table().map(|_| v)) {
+                expect.extend(try!(Matcher::from_toml(stream, val)));
+            }
+        }
+        if let (None, Some(val)) = (expect_status, tml.lookup("status")) {
+            expect.push(try!(Matcher::status_from_toml(val)));
+        }
         let abort_on_failure = unfold!(tml, "abort_on_failure", bool, optional, false);
-        match &*exec_type {
-            "console" => Ok(Exec {
-                exec_type: ExecType::Console,
-                host: unfold!(tml, "host", String, optional),
-                command: command,
-                expect_stdout: expect_stdout,
-                expect_stderr: expect_stderr,
-                expect_status: expect_status,
-                abort_on_failure: abort_on_failure
-            }),
-            "local" => Ok(Exec {
-                exec_type: ExecType::Local,
-                host: Some("localhost".to_string()),
-                command: command,
-                expect_stdout: expect_stdout,
-                expect_stderr: expect_stderr,
-                expect_status: expect_status,
-                abort_on_failure: abort_on_failure
-            }),
-            "ssh" => Ok(Exec {
-                exec_type: ExecType::Ssh,
-                host: unfold!(tml, "host", String, optional),
-                command: command,
-                expect_stdout: expect_stdout,
-                expect_stderr: expect_stderr,
-                expect_status: expect_status,
-                abort_on_failure: abort_on_failure,
-            }),
-            _ => Err("failed to build exec".into())
+        let always_run = unfold!(tml, "always_run", bool, optional, false);
+        let timeout = try!(non_negative("timeout", unfold!(tml, "timeout", i32, optional)));
+        let retries = try!(non_negative("retries", unfold!(tml, "retries", i32, optional)))
+                          .unwrap_or(0);
+        let retry_interval = try!(non_negative("retry_interval",
+                                               unfold!(tml, "retry_interval", i32, optional)))
+                                 .unwrap_or(5);
+        let wait_until = try!(non_negative("wait_until", unfold!(tml, "wait_until", i32, optional)));
+        let (exec_type, host, command) = match &*exec_type {
+            "agent" => {
+                (ExecType::Agent,
+                 unfold!(tml, "host", String, optional),
+                 unfold!(tml, "command", String))
+            },
+            "console" => {
+                (ExecType::Console,
+                 unfold!(tml, "host", String, optional),
+                 unfold!(tml, "command", String))
+            },
+            "local" => {
+                (ExecType::Local,
+                 Some("localhost".to_string()),
+                 unfold!(tml, "command", String))
+            },
+            "ssh" => {
+                (ExecType::Ssh,
+                 unfold!(tml, "host", String, optional),
+                 unfold!(tml, "command", String))
+            },
+            "upload" => {
+                let src = unfold!(tml, "src", PathBuf);
+                let dst = unfold!(tml, "dst", String);
+                let mode = match tml.lookup("mode") {
+                    Some(&toml::Value::String(ref s)) => {
+                        match i32::from_str_radix(s, 8) {
+                            Ok(mode) => Some(mode),
+                            Err(_) => return Err(format!("invalid mode: {}", s).into()),
+                        }
+                    },
+                    Some(_) => return Err("`mode` must be e.g. \"0644\"".into()),
+                    None => None,
+                };
+                let command = format!("upload {} to {}", src.display(), dst);
+                (ExecType::Upload { src: src, dst: dst, mode: mode },
+                 unfold!(tml, "host", String, optional),
+                 command)
+            },
+            "download" => {
+                let src = unfold!(tml, "src", String);
+                let dst = unfold!(tml, "dst", PathBuf);
+                let command = format!("download {} to {}", src, dst.display());
+                (ExecType::Download { src: src, dst: dst },
+                 unfold!(tml, "host", String, optional),
+                 command)
+            },
+            "script" => {
+                let path = unfold!(tml, "path", PathBuf);
+                if !path.is_file() {
+                    return Err(format!("no such script: {}", path.display()).into());
+                }
+                let mut args = Vec::new();
+                if let Some(vals) = tml.lookup("args").and_then(|v| v.as_slice()) {
+                    for val in vals.iter() {
+                        match val.as_str() {
+                            Some(arg) => args.push(arg.to_string()),
+                            None => return Err("`args` must be strings".into()),
+                        }
+                    }
+                }
+                let command = format!("script {} {}", path.display(), args.join(" "));
+                let md5 = try!(calc_md5(&path));
+                (ExecType::Script { path: path, args: args, md5: md5 },
+                 unfold!(tml, "host", String, optional),
+                 command.trim().to_string())
+            },
+            _ => return Err("failed to build exec".into())
+        };
+        Ok(Exec {
+            exec_type: exec_type,
+            host: host,
+            command: command,
+            env: env,
+            cwd: cwd,
+            user: user,
+            stdin: stdin,
+            pty: pty,
+            max_output: max_output,
+            expect_stdout: expect_stdout,
+            expect_stderr: expect_stderr,
+            expect_status: expect_status,
+            expect: expect,
+            abort_on_failure: abort_on_failure,
+            always_run: always_run,
+            timeout: timeout,
+            retries: retries,
+            retry_interval: retry_interval,
+            wait_until: wait_until,
+        })
+    }
+    /// The exec as of now, i.e. with md5 of its script as it is.
+    pub fn refreshed(&self) -> Exec {
+        let mut exec = self.clone();
+        if let ExecType::Script { ref path, ref mut md5, .. } = exec.exec_type {
+            if let Ok(current) = calc_md5(path) {
+                *md5 = current;
+            }
         }
+        exec
+    }
+    /// Everything expected of the output, exact ones first.
+    pub fn matchers(&self) -> Vec<Matcher> {
+        let mut matchers = Vec::new();
+        if let Some(ref stdout) = self.expect_stdout {
+            matchers.push(Matcher::Equals { stream: Stream::Stdout, value: stdout.clone() });
+        }
+        if let Some(ref stderr) = self.expect_stderr {
+            matchers.push(Matcher::Equals { stream: Stream::Stderr, value: stderr.clone() });
+        }
+        if let Some(status) = self.expect_status {
+            matchers.push(Matcher::StatusIn(vec![status]));
+        }
+        matchers.extend(self.expect.iter().cloned());
+        matchers
     }
 }
 
@@ -154,6 +373,7 @@ pub mod cluster;
 use self::setting::Setting;
 use self::template::Template;
 use self::cluster::Cluster;
+use self::cluster::watchpoint::WatchSchedule;
 
 #[derive(Debug)]
 pub struct Config {
@@ -184,7 +404,7 @@ impl Config {
     pub fn from_toml(tml: &toml::Value) -> Result<Config> {
         // global setting
         let setting = if let Some(val) = tml.lookup("setting") {
-            Arc::new(Setting::from_toml(&val))
+            Arc::new(try!(Setting::from_toml(&val)))
         } else {
             // if blank, default setting applied.
             Arc::new(Setting::default())
@@ -218,4 +438,34 @@ impl Config {
             clusters: clusters,
         })
     }
+    /// Watch the watchpoints added through the api along with those in
+    /// the config file, on their default schedules.
+    pub fn add_stored_watchpoints(&mut self, store: &Store) -> Result<()> {
+        let mut clusters = HashSet::new();
+        for cluster in self.clusters.iter() {
+            let added = try!(store.added_watchpoints(hash(&cluster.name)));
+            let mut cluster = (**cluster).clone();
+            for watchpoint in added {
+                if cluster.watchpoints.contains(&watchpoint) {
+                    continue;
+                }
+                cluster.watch_schedules.push(WatchSchedule::default_for(&watchpoint));
+                cluster.watchpoints.push(watchpoint);
+            }
+            clusters.insert(Arc::new(cluster));
+        }
+        self.clusters = clusters;
+        Ok(())
+    }
+    /// True if any script has changed since the config was read, which
+    /// is to be read again then for its execs to be saved as they are.
+    pub fn scripts_changed(&self) -> bool {
+        self.clusters.iter().any(|c| c.execs().into_iter().any(|e| *e != e.refreshed()))
+    }
+    pub fn save(&self, store: &Store) -> Result<()> {
+        for cluster in self.clusters.iter() {
+            try!(cluster.save(store));
+        }
+        Ok(())
+    }
 }
@@ -1,7 +1,101 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
 use toml;
+use url::Url;
 use ::consts::*;
+use ::util::errors::*;
 use ::util::ipv4::IPv4;
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum StoreKind {
+    /// Neo4j server described in `[setting.database]`.
+    Neo4j,
+    /// Embedded JSON files under the given directory.
+    File {
+        dir: PathBuf,
+    },
+}
+
+/// What to do with host keys of guests on connecting to them over ssh.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum HostKeyPolicy {
+    /// Accept whatever key.
+    Ignore,
+    /// Record the key of an unknown host into our own known_hosts under
+    /// its domain name, and refuse a host whose key differs from the
+    /// recorded one. Keys of the hosts of a template are forgotten once
+    /// its image is rebuilt or deleted.
+    Record,
+    /// Refuse a host unless its key is in our own known_hosts.
+    Strict,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Database {
+    /// Neo4j REST endpoint.
+    /// DEFAULT: "http://localhost:7474/db/data/"
+    pub url: String,
+    /// DEFAULT: "neo4j"
+    pub user: String,
+    /// DEFAULT: "neo4j"
+    pub password: Option<String>,
+    /// If set, password is read from this file instead, which
+    /// keeps it out of the config. A trailing newline is ignored.
+    /// DEFAULT: None
+    pub password_file: Option<PathBuf>,
+}
+
+impl Default for Database {
+    fn default() -> Database {
+        Database {
+            url: "http://localhost:7474/db/data/".to_string(),
+            user: "neo4j".to_string(),
+            password: Some("neo4j".to_string()),
+            password_file: None,
+        }
+    }
+}
+
+impl Database {
+    pub fn from_toml(tml: &toml::Value) -> Database {
+        let mut database = Database::default();
+        if let Some(val) = tml.lookup("url") {
+            database.url = val.as_str().unwrap().to_owned();
+        }
+        if let Some(val) = tml.lookup("user") {
+            database.user = val.as_str().unwrap().to_owned();
+        }
+        if let Some(val) = tml.lookup("password") {
+            database.password = Some(val.as_str().unwrap().to_owned());
+        }
+        if let Some(val) = tml.lookup("password_file") {
+            database.password_file = Some(PathBuf::from(val.as_str().unwrap()));
+        }
+        database
+    }
+    /// Endpoint with credentials embedded, as the graph client wants it.
+    pub fn endpoint(&self) -> Result<String> {
+        let password = match self.password_file {
+            Some(ref path) => {
+                let mut buf = String::new();
+                try!(try!(File::open(path)).read_to_string(&mut buf));
+                buf.trim_right_matches('\n').to_owned()
+            },
+            None => self.password.clone().unwrap_or(String::new()),
+        };
+        let mut url = match Url::parse(&self.url) {
+            Ok(u) => u,
+            Err(e) => return Err(format!("invalid database url {}: {}", self.url, e).into()),
+        };
+        if url.set_username(&self.user).is_err() ||
+           url.set_password(Some(&password)).is_err() {
+            return Err(format!("cannot set credentials to database url: {}", self.url).into());
+        }
+        Ok(url.as_str().to_owned())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Setting {
     /// Hypervisor uri to connect.
@@ -29,6 +123,17 @@ pub struct Setting {
     /// If true, run in daemon mode
     /// DEFAULT: false
     pub daemonized: bool,
+    /// Where to keep watchpoint perceptions and exec results,
+    /// either "neo4j" or "file". For the latter, `store_dir`
+    /// can point at the directory to use.
+    /// DEFAULT: "neo4j"
+    pub store: StoreKind,
+    /// Neo4j connection, read from `[setting.database]`.
+    pub database: Database,
+    /// Either "ignore", "record" or "strict". The known_hosts
+    /// for the latter two is the one under the data dir.
+    /// DEFAULT: "ignore"
+    pub host_key_policy: HostKeyPolicy,
 }
 
 impl Default for Setting {
@@ -41,12 +146,15 @@ impl Default for Setting {
             persistent: true,
             delete_unused_template: true,
             daemonized: false,
+            store: StoreKind::Neo4j,
+            database: Database::default(),
+            host_key_policy: HostKeyPolicy::Ignore,
         }
     }
 }
 
 impl Setting {
-    pub fn from_toml(tml: &toml::Value) -> Setting {
+    pub fn from_toml(tml: &toml::Value) -> Result<Setting> {
         let mut setting = Setting::default();
         if let Some(val) = tml.lookup("hypervisor") {
             setting.hypervisor = val.as_str().unwrap().to_owned();
@@ -63,6 +171,28 @@ impl Setting {
         if let Some(val) = tml.lookup("daemonized") {
             setting.daemonized = val.as_bool().unwrap();
         }
-        setting
+        if let Some(val) = tml.lookup("store") {
+            setting.store = match val.as_str().unwrap() {
+                "neo4j" => StoreKind::Neo4j,
+                "file" => StoreKind::File {
+                    dir: tml.lookup("store_dir")
+                            .map(|v| PathBuf::from(v.as_str().unwrap()))
+                            .unwrap_or(DATA_DIR.join("store")),
+                },
+                s => return Err(format!("unsupported store: {}", s).into()),
+            };
+        }
+        if let Some(val) = tml.lookup("database") {
+            setting.database = Database::from_toml(&val);
+        }
+        if let Some(val) = tml.lookup("host_key_policy") {
+            setting.host_key_policy = match val.as_str().unwrap() {
+                "ignore" => HostKeyPolicy::Ignore,
+                "record" => HostKeyPolicy::Record,
+                "strict" => HostKeyPolicy::Strict,
+                s => return Err(format!("unsupported host_key_policy: {}", s).into()),
+            };
+        }
+        Ok(setting)
     }
 }
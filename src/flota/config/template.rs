@@ -83,6 +83,12 @@ pub struct Template {
     pub mgmt_user: String,
     /// SSH private key path
     pub mgmt_user_ssh_private_key: PathBuf,
+    /// Passphrase of the private key, if any.
+    /// DEFAULT: None
+    pub mgmt_user_ssh_private_key_passphrase: Option<String>,
+    /// If true, try keys held by ssh-agent before the one above.
+    /// DEFAULT: false
+    pub mgmt_user_ssh_agent: bool,
     /// SSH public key path
     pub mgmt_user_ssh_public_key: PathBuf,
     /// Arc for global setting
@@ -90,21 +96,19 @@ pub struct Template {
 }
 
 impl Cypherable for Template {
-    fn cypher_ident(&self) -> String {
-        format!("Template {{ name: '{name}',
-                             arch: '{arch}',
-                             ingredient: '{ingredient:?}',
-                             ks: '{ks:?}',
-                             mgmt_user: '{mgmt_user}',
-                             mgmt_user_ssh_private_key: '{ssh_priv_key}',
-                             mgmt_user_ssh_public_key: '{ssh_pub_key}' }}",
-               name = self.name,
-               arch = self.arch,
-               ingredient = self.ingredient,
-               ks = self.ks,
-               mgmt_user = self.mgmt_user,
-               ssh_priv_key = self.mgmt_user_ssh_private_key.to_str().unwrap(),
-               ssh_pub_key = self.mgmt_user_ssh_public_key.to_str().unwrap())
+    fn cypher_props(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("name", self.name.clone()),
+            ("arch", self.arch.clone()),
+            ("ingredient", format!("{:?}", self.ingredient)),
+            ("ks", format!("{:?}", self.ks)),
+            ("mgmt_user", self.mgmt_user.clone()),
+            ("mgmt_user_ssh_private_key",
+             self.mgmt_user_ssh_private_key.to_str().unwrap().to_owned()),
+            ("mgmt_user_ssh_public_key",
+             self.mgmt_user_ssh_public_key.to_str().unwrap().to_owned()),
+            ("mgmt_user_ssh_agent", self.mgmt_user_ssh_agent.to_string()),
+        ]
     }
 }
 
@@ -125,6 +129,9 @@ impl Template {
         let mgmt_user_ssh_public_key = unfold!(
             val, "mgmt_user_ssh_public_key", PathBuf, optional,
             PathBuf::from(format!("/home/{}/.ssh/id_rsa.pub", mgmt_user)));
+        let mgmt_user_ssh_private_key_passphrase = unfold!(
+            val, "mgmt_user_ssh_private_key_passphrase", String, optional);
+        let mgmt_user_ssh_agent = unfold!(val, "mgmt_user_ssh_agent", bool, optional, false);
         Ok(Template {
             name: name,
             arch: arch,
@@ -132,6 +139,8 @@ impl Template {
             ks: ks,
             mgmt_user: mgmt_user,
             mgmt_user_ssh_private_key: mgmt_user_ssh_private_key,
+            mgmt_user_ssh_private_key_passphrase: mgmt_user_ssh_private_key_passphrase,
+            mgmt_user_ssh_agent: mgmt_user_ssh_agent,
             mgmt_user_ssh_public_key: mgmt_user_ssh_public_key,
             setting: setting.clone(),
         })
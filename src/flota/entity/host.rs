@@ -1,8 +1,11 @@
 use nix::unistd::sleep;
 use std::ops::Deref;
 use std::sync::Arc;
-use ::exec::session;
 use ::exec::session::*;
+use ::exec::session::agent::SessSeedAgent;
+use ::exec::session::cache::SessionCache;
+use ::exec::session::console::SessSeedConsole;
+use ::exec::session::local::SessSeedLocal;
 use ::exec::session::ssh::SessSeedSsh;
 use ::flota::config;
 use ::flota::entity::template;
@@ -16,6 +19,8 @@ use ::virt::storage::volume::*;
 pub struct Host<'a> {
     pub domain: Domain,
     pub template: Arc<template::Template<'a>>,
+    /// Sessions to the host, reused by all of its execs.
+    pub sessions: SessionCache,
 }
 
 impl<'a> Host<'a> {
@@ -61,31 +66,51 @@ impl<'a> Host<'a> {
         // get mgmt interface's ip address
         let mgmt_ip = dom.ip_in_network(template.resources.network().unwrap()).unwrap();
 
-        // if session seed type is ssh, we update ip
-        // because we had not known what management ip it would have.
+        // seeds of the template know nothing about which host they are
+        // for, as we had not known what management ip it would have.
         let mut seeds = template.session_seeds.clone();
         for mut seed in seeds.iter_mut() {
-            if seed.seed_type() == SeedType::Ssh {
-                seed.as_mut_any()
-                    .downcast_mut::<SessSeedSsh>()
-                    .map(|s| s.override_ip(&mgmt_ip));
+            match seed.seed_type() {
+                SeedType::Ssh => {
+                    seed.as_mut_any()
+                        .downcast_mut::<SessSeedSsh>()
+                        .map(|s| {
+                            s.override_ip(&mgmt_ip);
+                            s.override_domain(dom.name());
+                        });
+                },
+                SeedType::Agent => {
+                    seed.as_mut_any()
+                        .downcast_mut::<SessSeedAgent>()
+                        .map(|s| s.override_domain(dom.name()));
+                },
+                SeedType::Console => {
+                    seed.as_mut_any()
+                        .downcast_mut::<SessSeedConsole>()
+                        .map(|s| s.override_domain(dom.name()));
+                },
+                SeedType::Local => {},
             }
         }
+        // local execs run on our side, whatever the template provides.
+        seeds.push(SessSeedLocal::new());
+        let sessions = SessionCache::new(seeds);
 
         // wait at most 60 seconds until guest-side sshd boots up.
         'try_adaption: for _ in 0..20 {
-            match session::try_spawn(&seeds, vec![SeedType::Ssh]) {
+            match sessions.get(SeedType::Ssh) {
                 Ok(session) => {
-                    if let Err(_) = template.distro.deref()
-                        .adapt_network_state(
-                            &host, unsafe { &*Box::into_raw(session) },
-                            &dom, &template.resources
-                        ) {
+                    let adapted = template.distro.deref()
+                        .adapt_network_state(&host, &**session, &dom, &template.resources);
+                    // networking has been restarted, which the session may
+                    // or may not have survived.
+                    sessions.invalidate();
+                    if let Err(_) = adapted {
                         // XXX: if we seem to have failed to adapt network state,
                         //      we try to connect again. max retry count is ten, sleep
                         //      interval is 3sec.
                         'wait_wakeup: for i in 0..10 {
-                            match session::try_spawn(&seeds, vec![SeedType::Ssh]) {
+                            match sessions.get(SeedType::Ssh) {
                                 Err(_) if i >= 9 => { break 'wait_wakeup },
                                 Ok(_) => { break 'try_adaption },
                                 _ => {
@@ -115,6 +140,7 @@ impl<'a> Host<'a> {
         Ok(Host {
             domain: dom,
             template: template.clone(),
+            sessions: sessions,
         })
     }
     pub fn shutdown(&self) -> Result<()> {
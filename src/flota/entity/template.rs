@@ -1,9 +1,13 @@
 use ::flota::config;
 use ::distro;
+use ::distro::Distros;
 use ::exec::session::*;
-use ::exec::session::ssh::SessSeedSsh;
+use ::exec::session::agent::SessSeedAgent;
+use ::exec::session::console::SessSeedConsole;
+use ::exec::session::ssh::{forget_host_keys, SessSeedSsh, SshAuth};
 use ::util::errors::*;
 use ::virt::*;
+use ::virt::domain::Domain;
 use ::virt::domain::snapshot::*;
 
 #[derive(Clone, Debug)]
@@ -21,6 +25,8 @@ impl<'a> Template<'a> {
                distro: Box<distro::Distro>)
                -> Result<Self> {
 
+        // the image is installed only if its domain is not there yet.
+        let installed = Domain::find(&distro.ident(), resources.conn()).is_none();
         let (dom, volume) = distro.build_image(None,
                          resources.conn(),
                          resources.pool().as_ref().unwrap(),
@@ -33,15 +39,37 @@ impl<'a> Template<'a> {
         let snapshot = DomainSnapshot::ensure(&dom, &pool_root, Some(&snapshot_name));
 
         try!(dom.destroy());
+        // hosts of the template come up with the keys of the new image.
+        if installed {
+            try!(forget_host_keys(&template.name));
+        }
 
-        // XXX: now its okay to have only one choice, maybe not?
-        let session_seeds = vec![
+        // console, and agent where the distro has one, are there for when the
+        // network is not. the password of mgmt user is its name, as set on
+        // unattended installation.
+        let auth = SshAuth {
+            agent: template.mgmt_user_ssh_agent,
+            priv_key: template.mgmt_user_ssh_private_key.clone(),
+            passphrase: template.mgmt_user_ssh_private_key_passphrase.clone(),
+            password: Some(template.mgmt_user.clone()),
+        };
+        let mut session_seeds = vec![
             SessSeedSsh::new(
                 &template.mgmt_user,
                 None, 22,
-                template.mgmt_user_ssh_private_key.as_path()
-            )
+                auth,
+                &template.setting.host_key_policy,
+                &template.name
+            ),
+            SessSeedConsole::new(
+                &try!(resources.conn().uri()),
+                &template.mgmt_user,
+                &template.mgmt_user
+            ),
         ];
+        if distro.has_guest_agent() {
+            session_seeds.push(SessSeedAgent::new(&try!(resources.conn().uri())));
+        }
 
         Ok(Template {
             name: template.name.to_owned(),
@@ -51,6 +79,24 @@ impl<'a> Template<'a> {
             distro: distro,
         })
     }
+    /// Build the template with the distro its ingredient points to.
+    pub fn from_config(resources: &'a ResourceBlend,
+                       template: &config::template::Template)
+                       -> Result<Self> {
+        Self::new(resources, template, try!(Distros::of(template)))
+    }
+    /// Remove the image built for the template, i.e. its domain along
+    /// with the snapshot and volumes it is made of.
+    pub fn delete_image(resources: &ResourceBlend,
+                        template: &config::template::Template)
+                        -> Result<()> {
+        let ident = try!(Distros::of(template)).ident();
+        try!(forget_host_keys(&template.name));
+        match Domain::find(&ident, resources.conn()) {
+            Some(dom) => remove_domain(resources.conn(), resources.pool(), &dom),
+            None => Ok(()),
+        }
+    }
     pub fn destroy(&mut self) -> () {
         unimplemented!()
     }
@@ -1,215 +1,288 @@
-use rusted_cypher::graph::GraphClient;
+use nix::unistd::sleep;
+use std::collections::BTreeMap;
+use std::fs;
 use std::sync::Arc;
+use time;
 use ::exec::{ExecResult, Output};
-use ::exec::session::SeedType;
-use ::exec::session::ssh::SessSeedSsh;
-use ::flota::{config, Cypherable};
+use ::exec::matcher::{check_all, MatchFailure};
+use ::exec::session::{exec_script, ExecOptions, SeedType, Session};
+use ::exec::session::cache::SessionCache;
+use ::exec::session::local::SessSeedLocal;
+use ::flota::config;
 use ::flota::entity::template;
 use ::flota::entity::host::Host;
+use ::flota::hash;
+use ::flota::runlog::RunLogs;
+use ::flota::spool::Spool;
+use ::flota::store::{History, RunState, Store};
 use ::flota::test::Cause;
 use ::util::errors::*;
+use ::virt::domain::Domain;
 
+pub mod schedule;
 pub mod watch;
 use self::watch::WatchPointPerception;
 
-pub struct Manager {}
+pub struct Manager {
+    store: Arc<Store>,
+    spool: Spool,
+    logs: RunLogs,
+}
 
 impl Manager {
-    fn pin_cluster_watchpoints(cluster: &config::cluster::Cluster)
-                               -> Result<()> {
-        // prepare and start transaction
-        let graph = GraphClient::connect(::NEO4J_ENDPOINT).unwrap();
-        let mut transaction = graph.cypher().transaction();
-        transaction.add_statement("MATCH (n: TRANSACTION) RETURN n");
-        let (mut transaction, _) = transaction.begin().unwrap();
-
-        // update perception linked-lists
-        for ref watchpoint in &cluster.watchpoints {
-            let current_perception = WatchPointPerception::new(watchpoint);
-            try!(save_child_ll!(&mut transaction, watchpoint, current_perception, "IS_SNAPSHOT_OF")
-                                .map(|_| ()));
+    pub fn new(store: Arc<Store>, spool: Spool, logs: RunLogs) -> Self {
+        Manager {
+            store: store,
+            spool: spool,
+            logs: logs,
         }
-
-        // commit transaction
-        transaction.commit().unwrap();
-        Ok(())
     }
-    fn cause_of_next_cluster_run(cluster: &config::cluster::Cluster)
-                                 -> Result<Vec<Cause>> {
-        if let Ok(true) = cluster.is_first_run() {
-            try!(Self::pin_cluster_watchpoints(cluster));
-            return Ok(vec![ Cause::FirstRun ])
+    /// Perceive the watchpoint, which is a cause to run the clusters
+    /// watching it if it has changed since it was last perceived.
+    pub fn perceive(&self, watchpoint: &config::cluster::watchpoint::WatchPoint)
+                    -> Result<Option<Cause>> {
+        let current_perception = try!(WatchPointPerception::new(watchpoint));
+        if let Ok(true) = self.store.is_tail_perception(watchpoint, &current_perception) {
+            return Ok(None)
         }
-
-        // if we perceive some watchpoint state have changed since last run,
-        // it needs to re-run test.
-        let mut causes = vec![];
-        // prepare and start transaction
-        let graph = GraphClient::connect(::NEO4J_ENDPOINT).unwrap();
-        let mut transaction = graph.cypher().transaction();
-        transaction.add_statement("MATCH (n: TRANSACTION) RETURN n");
-        let (mut transaction, _) = transaction.begin().unwrap();
-        for ref watchpoint in &cluster.watchpoints {
-            let current_perception = WatchPointPerception::new(watchpoint);
-            match is_tail!(watchpoint, current_perception) {
-                Ok(true) => {},
-                _ => {
-                    // XXX:
-                    try!(save_child_ll!(&mut transaction, watchpoint, &current_perception,
-                                        "IS_SNAPSHOT_OF").map(|_| ()));
-                    causes.push(Cause::WatchPoint { ident: current_perception });
+        let changed = match try!(self.store.perceptions(watchpoint)).first() {
+            Some(previous) => current_perception.value.changed_since(&previous.value),
+            None => vec![],
+        };
+        try!(self.store.append_perception(watchpoint, &current_perception));
+        Ok(Some(Cause::WatchPoint {
+            ident: current_perception,
+            changed: changed,
+        }))
+    }
+    fn record(&self, exec: &config::Exec, result: ExecResult, history: &mut History)
+              -> Result<()> {
+        try!(self.store.append_exec_result(exec, &result, &history.causes));
+        history.push(result);
+        try!(self.store.save_history(history));
+        self.ensure_not_cancelled(history)
+    }
+    // exec with its output logged as it arrives, for the api to stream.
+    fn exec_logged(&self, sess: &Session, host: &str, one_exec: &config::Exec,
+                   history: &History) -> Result<Output> {
+        let command = &one_exec.command;
+        let mut log = try!(self.logs.exec(history.id, host, command));
+        // file transfers have no output but whether they succeeded.
+        let transferred = Output {
+            stdout: None,
+            stderr: None,
+            status: Some(0),
+        };
+        let ret = match one_exec.exec_type {
+            config::ExecType::Upload { ref src, ref dst, mode } => {
+                sess.put(src, dst, mode).map(|_| transferred)
+            },
+            config::ExecType::Download { ref src, ref dst } => {
+                sess.get(src, dst).map(|_| transferred)
+            },
+            config::ExecType::Script { ref path, ref args, ref md5 } => {
+                exec_script(sess, path, args, md5, &ExecOptions::from_exec(one_exec), &mut log)
+            },
+            _ => sess.exec_streamed(command, &ExecOptions::from_exec(one_exec), &mut log),
+        };
+        log.end(ret.as_ref().ok().and_then(|o| o.status));
+        ret
+    }
+    // run and record it. transport errors, timeouts included, count as
+    // a failure as well. re-run as long as retries or wait_until allow,
+    // and only the last one is recorded. the session is taken from the
+    // cache on each run, so that one lost e.g. on a reboot is replaced.
+    fn run_exec(&self, sessions: &SessionCache, seed_type: SeedType, hostname: &str,
+                one_exec: &config::Exec, history: &mut History) -> Result<bool> {
+        let expected = Output {
+            stdout: one_exec.expect_stdout.clone(),
+            stderr: one_exec.expect_stderr.clone(),
+            status: one_exec.expect_status.clone(),
+        };
+        // recorded for the content its script has at the time.
+        let refreshed = one_exec.refreshed();
+        let one_exec = &refreshed;
+        let matchers = one_exec.matchers();
+        let deadline = one_exec.wait_until.map(|secs| time::get_time().sec + secs as i64);
+        let mut attempts = 0;
+        loop {
+            let ret = sessions.get(seed_type).and_then(|sess| {
+                self.exec_logged(&**sess, hostname, one_exec, history)
+            });
+            let ret = match ret {
+                Ok(ret) => {
+                    info!("{}", ret);
+                    ret
                 },
+                Err(e) => {
+                    error!("{}", e);
+                    Output {
+                        stdout: None,
+                        stderr: Some(e.to_string()),
+                        status: None,
+                    }
+                }
+            };
+            let failure = check_all(&matchers, &ret);
+            attempts += 1;
+            let again = failure.is_some() && (attempts <= one_exec.retries ||
+                deadline.map(|d| time::get_time().sec + (one_exec.retry_interval as i64) < d)
+                        .unwrap_or(false));
+            if again {
+                info!("re-running in {}s (attempt {}): {}",
+                      one_exec.retry_interval, attempts + 1, one_exec.command);
+                try!(self.ensure_not_cancelled(history));
+                sleep(one_exec.retry_interval);
+                continue;
+            }
+            if let Some(ref failure) = failure {
+                info!("unsatisfied {}:\n{}", failure.matcher, failure.detail);
             }
+            return self.record_result(hostname, one_exec, expected, ret, failure, history);
         }
-        try!(transaction.commit());
-        Ok(causes)
     }
-    pub fn run_host_test(config: &config::cluster::host::Host,
-                         host: &Host,
-                         causes: &Vec<Cause>) -> Result<()> {
-        // XXX: duplicate code
-        let mgmt_ip = host.domain.ip_in_network(host.template.resources.network().unwrap())
-                                 .unwrap();
-        let mut seeds = host.template.session_seeds.clone();
-        for mut seed in seeds.iter_mut() {
-            if seed.seed_type() == SeedType::Ssh {
-                seed.as_mut_any()
-                    .downcast_mut::<SessSeedSsh>()
-                    .map(|s| s.override_ip(&mgmt_ip));
-            }
+    fn record_result(&self, hostname: &str, one_exec: &config::Exec, expected: Output,
+                     ret: Output, failure: Option<MatchFailure>, history: &mut History)
+                     -> Result<bool> {
+        let passed = failure.is_none();
+        let result = ExecResult {
+            host: hostname.to_string(),
+            command: one_exec.command.clone(),
+            expected: expected,
+            result: ret,
+            passed: passed,
+            failure: failure,
+            skipped: false,
+        };
+        try!(self.record(one_exec, result, history));
+        Ok(passed)
+    }
+    fn skip_exec(&self, hostname: &str, one_exec: &config::Exec, history: &mut History)
+                 -> Result<()> {
+        let result = ExecResult {
+            host: hostname.to_string(),
+            command: one_exec.command.clone(),
+            expected: Output {
+                stdout: one_exec.expect_stdout.clone(),
+                stderr: one_exec.expect_stderr.clone(),
+                status: one_exec.expect_status.clone(),
+            },
+            result: Output {
+                stdout: None,
+                stderr: None,
+                status: None,
+            },
+            passed: false,
+            failure: None,
+            skipped: true,
+        };
+        self.record(one_exec, result, history)
+    }
+    // failed rather than skipped, as nothing would tell it had not run.
+    fn unprovided_exec(&self, hostname: &str, one_exec: &config::Exec, seed_type: SeedType,
+                       history: &mut History) -> Result<bool> {
+        let detail = format!("no {:?} session is provided of {}", seed_type, hostname);
+        error!("{}", detail);
+        let expected = Output {
+            stdout: one_exec.expect_stdout.clone(),
+            stderr: one_exec.expect_stderr.clone(),
+            status: one_exec.expect_status.clone(),
+        };
+        let ret = Output {
+            stdout: None,
+            stderr: Some(detail.clone()),
+            status: None,
+        };
+        let failure = MatchFailure {
+            matcher: "session".to_string(),
+            detail: detail,
+        };
+        self.record_result(hostname, one_exec, expected, ret, Some(failure), history)
+    }
+    fn ensure_not_cancelled(&self, history: &History) -> Result<()> {
+        if self.spool.is_cancelled(history.id) {
+            return Err(format!("run {} cancelled", history.id).into());
         }
-
+        Ok(())
+    }
+    /// Returns true if aborted, in which case only execs with always_run
+    /// have been run after that.
+    pub fn run_host_test(&self,
+                         config: &config::cluster::host::Host,
+                         host: &Host,
+                         history: &mut History) -> Result<bool> {
+        let mut aborted = false;
         for tests in vec![
             &config.solo_pre_tests,
             &config.solo_tests,
             &config.solo_post_tests
         ].iter() {
             for one_exec in tests.iter() {
+                if aborted && !one_exec.always_run {
+                    try!(self.skip_exec(&config.hostname, one_exec, history));
+                    continue;
+                }
                 if let Some(seed_type) = SeedType::from_exec_type(&one_exec.exec_type) {
-                    if let Some(ref seed) = seeds.iter().find(|s| s.seed_type() == seed_type) {
-                        let sess = seed.spawn().unwrap();
-                        let expected = Output {
-                            stdout: one_exec.expect_stdout.clone(),
-                            stderr: one_exec.expect_stderr.clone(),
-                            status: one_exec.expect_status.clone(),
-                        };
-                        match sess.exec(&one_exec.command) {
-                            Ok(ret) => {
-                                info!("{}", ret);
-                                let passed = ret.satisfy(&expected);
-                                let result = ExecResult {
-                                    host: config.hostname.clone(),
-                                    command: one_exec.command.clone(),
-                                    expected: expected,
-                                    result: ret.clone(),
-                                    passed: passed,
-                                };
-                                // prepare and start transaction
-                                let graph = GraphClient::connect(::NEO4J_ENDPOINT).unwrap();
-                                let mut transaction = graph.cypher().transaction();
-                                transaction.add_statement("MATCH (n: TRANSACTION) RETURN n");
-                                let (mut transaction, _) = transaction.begin().unwrap();
-
-                                try!(save_child_ll!(&mut transaction, one_exec,
-                                                    result, "IS_RESULT_OF")
-                                     .map(|_| ()));
-                                for cause in causes.iter() {
-                                    if let Cause::WatchPoint { ref ident } = *cause {
-                                        try!(save_child_ll!(&mut transaction, ident,
-                                                            result, "DUE_TO")
-                                            .map(|_| ()));
-                                    }
-                                }
-                                // commit transaction
-                                try!(transaction.commit());
-                            },
-                            Err(e) => {
-                                error!("{}", e);
-                            }
-                        }
+                    let passed = if host.sessions.provides(seed_type) {
+                        try!(self.run_exec(&host.sessions, seed_type,
+                                           &config.hostname, one_exec, history))
+                    } else {
+                        try!(self.unprovided_exec(&config.hostname, one_exec, seed_type,
+                                                  history))
+                    };
+                    if !passed && one_exec.abort_on_failure {
+                        warn!("aborted on {}: {}", config.hostname, one_exec.command);
+                        aborted = true;
                     }
                 } else { panic!("would not panic") }
             }
         }
-        Ok(())
+        Ok(aborted)
     }
-    pub fn run_cluster_test(cluster: &config::cluster::Cluster,
+    /// Same as run_host_test, but starts off aborted if `aborted` is set.
+    pub fn run_cluster_test(&self,
+                            cluster: &config::cluster::Cluster,
                             hosts: &Vec<Host>,
-                            causes: &Vec<Cause>) -> Result<()> {
+                            aborted: bool,
+                            history: &mut History) -> Result<bool> {
+        let mut aborted = aborted;
+        let local = SessionCache::new(vec![SessSeedLocal::new()]);
         for tests in vec![
             &cluster.pre_tests,
             &cluster.tests,
             &cluster.post_tests
         ].iter() {
             for one_exec in tests.iter() {
+                if aborted && !one_exec.always_run {
+                    let hostname = one_exec.host.clone().unwrap_or(cluster.name.clone());
+                    try!(self.skip_exec(&hostname, one_exec, history));
+                    continue;
+                }
+                // local execs run on our side, e.g. to reach the cluster
+                // from the outside.
+                if one_exec.exec_type == config::ExecType::Local {
+                    let passed = try!(self.run_exec(&local, SeedType::Local, "localhost",
+                                                    one_exec, history));
+                    if !passed && one_exec.abort_on_failure {
+                        warn!("aborted on localhost: {}", one_exec.command);
+                        aborted = true;
+                    }
+                    continue;
+                }
                 // XXX: just ugly. help me.
                 // XXX: lazy validation might be a bad choice.
                 if let Some(host) = hosts.iter().find(|h| Some(h.domain.name().to_string()) == one_exec.host) {
                     if let Some(seed_type) = SeedType::from_exec_type(&one_exec.exec_type) {
-                        if let Some(seed) = host.template.session_seeds.iter().find(|s| {
-                            s.seed_type() == seed_type
-                        }) {
-                            let sess = {
-                                // if session seed type is ssh, we update ip
-                                // because we had not known what management ip it would have.
-                                if seed_type == SeedType::Ssh {
-                                    let mut seed_updated = seed.clone();
-                                    let mgmt_ip = host.domain
-                                        .ip_in_network(host.template.resources.network().unwrap())
-                                        .unwrap();
-                                    seed_updated.as_mut_any()
-                                                .downcast_mut::<SessSeedSsh>()
-                                                .map(|s| s.override_ip(&mgmt_ip));
-                                    seed_updated.spawn().unwrap()
-                                } else {
-                                    seed.spawn().unwrap()
-                                }
-                            };
-                            let expected = Output {
-                                stdout: one_exec.expect_stdout.clone(),
-                                stderr: one_exec.expect_stderr.clone(),
-                                status: one_exec.expect_status.clone(),
-                            };
-                            match sess.exec(&one_exec.command) {
-                                Ok(ref ret) => {
-                                    info!("{}", ret);
-                                    let passed = ret.satisfy(&expected);
-                                    let result = ExecResult {
-                                        host: match one_exec.host {
-                                            Some(ref hostname) => { hostname.clone() },
-                                            None => { unreachable!() },
-                                        },
-                                        command: one_exec.command.clone(),
-                                        expected: expected,
-                                        result: ret.clone(),
-                                        passed: passed,
-                                    };
-                                    // prepare and start transaction
-                                    let graph = GraphClient::connect(::NEO4J_ENDPOINT).unwrap();
-                                    let mut transaction = graph.cypher().transaction();
-                                    transaction.add_statement("MATCH (n: TRANSACTION) RETURN n");
-                                    let (mut transaction, _) = transaction.begin().unwrap();
-
-                                    try!(save_child_ll!(&mut transaction, one_exec,
-                                                        result, "IS_RESULT_OF")
-                                        .map(|_| ()));
-                                    for cause in causes.iter() {
-                                        if let Cause::WatchPoint { ref ident } = *cause {
-                                            try!(save_child_ll!(&mut transaction, ident,
-                                                                result, "DUE_TO")
-                                                 .map(|_| ()));
-                                        }
-                                    }
-
-                                    // commit transaction
-                                    try!(transaction.commit());
-                                },
-                                Err(e) => {
-                                    error!("{}", e);
-                                }
-                            }
+                        let passed = if host.sessions.provides(seed_type) {
+                            try!(self.run_exec(&host.sessions, seed_type,
+                                               host.domain.name(), one_exec, history))
                         } else {
-                            error!("requested method is not provided of that host");
+                            try!(self.unprovided_exec(host.domain.name(), one_exec, seed_type,
+                                                      history))
+                        };
+                        if !passed && one_exec.abort_on_failure {
+                            warn!("aborted on {}: {}", host.domain.name(), one_exec.command);
+                            aborted = true;
                         }
                     } else {
                         panic!("would not panic")
@@ -217,17 +290,18 @@ impl Manager {
                 }
             }
         }
-        Ok(())
+        Ok(aborted)
     }
-    pub fn run_cluster<'a>(cluster: &config::cluster::Cluster,
-                           templates: &Vec<Arc<template::Template<'a>>>)
-                       -> Result<bool> {
-        let causes = try!(Manager::cause_of_next_cluster_run(&cluster));
-        if causes.len() == 0 {
-            return Ok(false)
-        }
-        let mut hosts = Vec::new();
+    // true if aborted. an abort on any host skips the cluster tests too.
+    fn provision_and_test<'a>(&self,
+                              cluster: &config::cluster::Cluster,
+                              templates: &Vec<Arc<template::Template<'a>>>,
+                              hosts: &mut Vec<Host<'a>>,
+                              history: &mut History)
+                              -> Result<bool> {
+        let mut aborted = false;
         for host_config in cluster.hosts.iter() {
+            try!(self.ensure_not_cancelled(history));
             // search for a template matched to the host
             let template = match templates.iter().find(
                 |&t| t.name == host_config.template.name) {
@@ -236,13 +310,13 @@ impl Manager {
             };
             match Host::new(host_config, &template) {
                 Ok(host) => {
-                    if let Ok(_) = Manager::run_host_test(host_config,
-                                                          &host,
-                                                          &causes) {
-                        // for cluster tests later
-                        hosts.push(host);
-                    } else {
-                        panic!("would not panic")
+                    // kept for cluster tests later, and to be torn down
+                    // even if its own tests fail.
+                    hosts.push(host);
+                    if try!(self.run_host_test(host_config,
+                                               hosts.last().unwrap(),
+                                               history)) {
+                        aborted = true;
                     }
                 },
                 Err(e) => {
@@ -251,16 +325,79 @@ impl Manager {
                 },
             }
         }
-        if let Ok(_) = Manager::run_cluster_test(cluster,
-                                                 &hosts,
-                                                 &causes) {
-            // all done. shutdown hosts
-            for host in hosts.iter() {
-                try!(host.shutdown());
-            }
-        } else {
-            panic!("would not panic")
+        self.run_cluster_test(cluster, hosts, aborted, history)
+    }
+    pub fn run_cluster<'a>(&self,
+                           cluster: &config::cluster::Cluster,
+                           templates: &Vec<Arc<template::Template<'a>>>,
+                           causes: Vec<Cause>)
+                       -> Result<bool> {
+        // runs requested through the api go first, one per call.
+        let requested = try!(self.spool.take_run(hash(&cluster.name)));
+        let causes = match cluster.is_first_run(&*self.store) {
+            Ok(true) => vec![ Cause::FirstRun ],
+            _ => causes,
+        };
+        let mut history = match requested {
+            Some(mut history) => {
+                history.config_id = cluster.id();
+                history.causes.extend(causes);
+                history
+            },
+            None if causes.len() == 0 => return Ok(false),
+            None => History::new(cluster, &causes),
+        };
+        if self.spool.is_cancelled(history.id) {
+            history.state = RunState::Cancelled;
+            try!(self.store.save_history(&history));
+            try!(self.spool.clear_cancel(history.id));
+            return Ok(false)
         }
+        try!(self.run(cluster, templates, &mut history));
         Ok(true)
     }
+    /// Run the cluster for the history no matter what its watchpoints say.
+    pub fn run<'a>(&self,
+                   cluster: &config::cluster::Cluster,
+                   templates: &Vec<Arc<template::Template<'a>>>,
+                   history: &mut History)
+                   -> Result<()> {
+        history.state = RunState::Running;
+        try!(self.store.save_history(history));
+        let mut offsets = BTreeMap::new();
+        for host in cluster.hosts.iter() {
+            let len = fs::metadata(Domain::serial_log_path(&host.hostname))
+                          .map(|m| m.len())
+                          .unwrap_or(0);
+            offsets.insert(host.hostname.clone(), len);
+        }
+        try!(self.logs.mark_consoles(history.id, &offsets));
+
+        let mut hosts = Vec::new();
+        let ret = self.provision_and_test(cluster, templates, &mut hosts, history);
+
+        // all done, or given up half way. shutdown hosts
+        for host in hosts.iter() {
+            if let Err(e) = host.shutdown() {
+                error!("{}", e);
+            }
+        }
+        history.state = if self.spool.is_cancelled(history.id) {
+            RunState::Cancelled
+        } else {
+            match ret {
+                Ok(true) => RunState::Aborted,
+                Ok(false) => RunState::Finished,
+                // not a pass, however few results there are.
+                Err(ref e) => {
+                    history.passed = false;
+                    history.error = Some(e.to_string());
+                    RunState::Failed
+                },
+            }
+        };
+        try!(self.spool.clear_cancel(history.id));
+        try!(self.store.save_history(history));
+        ret.map(|_| ())
+    }
 }
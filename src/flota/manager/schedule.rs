@@ -0,0 +1,209 @@
+use notify;
+use notify::{RecommendedWatcher, Watcher};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use time;
+use ::flota::config::cluster::Cluster;
+use ::flota::config::cluster::schedule::CronSchedule;
+use ::flota::config::cluster::watchpoint::WatchPoint;
+use ::flota::test::Cause;
+use super::Manager;
+
+// Longest wait after consecutive failures to perceive, in seconds.
+const MAX_BACKOFF: u64 = 3600;
+// Notifications tend to come in bursts, e.g. while a file is written.
+const SETTLE: u64 = 1;
+// Minutes missed while e.g. a long run blocks the loop are caught up
+// with, but not for longer than a day.
+const MAX_CATCH_UP: i64 = 24 * 60;
+
+struct Entry {
+    watchpoint: WatchPoint,
+    // names of the clusters watching it.
+    clusters: Vec<String>,
+    poll_interval: u32,
+    failures: u32,
+    due: Instant,
+}
+
+impl Entry {
+    // Directory to be notified of changes in, if it is of files.
+    fn watched_dir(&self) -> Option<PathBuf> {
+        match self.watchpoint {
+            // the file itself might well be replaced by a rename.
+            WatchPoint::File { ref path } => path.parent().map(|p| p.to_path_buf()),
+            WatchPoint::Dir { ref path, .. } => Some(path.clone()),
+            _ => None,
+        }
+    }
+    fn is_affected_by(&self, event: &notify::Event) -> bool {
+        match (&self.watchpoint, &event.path) {
+            (&WatchPoint::File { ref path }, &Some(ref changed)) => changed == path,
+            (&WatchPoint::Dir { ref path, .. }, &Some(ref changed)) => changed.starts_with(path),
+            // no idea what has changed.
+            (&WatchPoint::File { .. }, &None) | (&WatchPoint::Dir { .. }, &None) => true,
+            _ => false,
+        }
+    }
+}
+
+fn current_minute() -> i64 {
+    time::get_time().sec / 60
+}
+
+/// Perceives each watchpoint on its own schedule, or soon after its files
+/// are notified to have changed, and keeps the clusters watching those
+/// which have changed dirty until they are taken. A watchpoint shared by
+/// clusters is perceived once for all of them. Clusters with a schedule
+/// are made dirty on it as well.
+pub struct WatchScheduler {
+    entries: Vec<Entry>,
+    crons: Vec<(String, CronSchedule)>,
+    // minutes since epoch up to which the crons have been evaluated.
+    ticked: i64,
+    dirty: BTreeMap<String, Vec<Cause>>,
+    events: Receiver<notify::Event>,
+    // kept so that notifications keep coming.
+    #[allow(dead_code)]
+    watcher: Option<RecommendedWatcher>,
+}
+
+impl WatchScheduler {
+    /// All the watchpoints are due at first.
+    pub fn new<'a, I>(clusters: I) -> Self
+        where I: IntoIterator<Item = &'a Arc<Cluster>>
+    {
+        let now = Instant::now();
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut crons = Vec::new();
+        for cluster in clusters {
+            if let Some(ref schedule) = cluster.schedule {
+                crons.push((cluster.name.clone(), schedule.clone()));
+            }
+            for (watchpoint, schedule) in cluster.watchpoints
+                                                 .iter()
+                                                 .zip(cluster.watch_schedules.iter()) {
+                if let Some(entry) = entries.iter_mut().find(|e| e.watchpoint == *watchpoint) {
+                    entry.clusters.push(cluster.name.clone());
+                    entry.poll_interval = cmp::min(entry.poll_interval, schedule.poll_interval);
+                    continue;
+                }
+                entries.push(Entry {
+                    watchpoint: watchpoint.clone(),
+                    clusters: vec![ cluster.name.clone() ],
+                    poll_interval: schedule.poll_interval,
+                    failures: 0,
+                    due: now,
+                });
+            }
+        }
+        let (tx, rx) = channel();
+        let watcher = match RecommendedWatcher::new(tx) {
+            Ok(mut watcher) => {
+                for dir in entries.iter().filter_map(|e| e.watched_dir()) {
+                    // polled all the same.
+                    if let Err(e) = watcher.watch(&dir) {
+                        warn!("failed to watch {}: {}", dir.display(), e);
+                    }
+                }
+                Some(watcher)
+            },
+            Err(e) => {
+                warn!("watchpoints are only polled: {}", e);
+                None
+            },
+        };
+        WatchScheduler {
+            entries: entries,
+            crons: crons,
+            // only minutes which begin after it starts.
+            ticked: current_minute(),
+            dirty: BTreeMap::new(),
+            events: rx,
+            watcher: watcher,
+        }
+    }
+    /// Wait until any watchpoint is due, for no longer than `max`.
+    pub fn wait(&mut self, max: Duration) {
+        let now = Instant::now();
+        let mut until = now + max;
+        if let Some(due) = self.entries.iter().map(|e| e.due).min() {
+            until = cmp::min(until, due);
+        }
+        loop {
+            let now = Instant::now();
+            if now >= until {
+                return;
+            }
+            match self.events.recv_timeout(until - now) {
+                Ok(event) => {
+                    let settled = Instant::now() + Duration::from_secs(SETTLE);
+                    for entry in self.entries.iter_mut().filter(|e| e.is_affected_by(&event)) {
+                        entry.due = cmp::min(entry.due, settled);
+                        until = cmp::min(until, settled);
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => return,
+                // nothing to be notified of.
+                Err(RecvTimeoutError::Disconnected) => {
+                    thread::sleep(until - now);
+                    return;
+                },
+            }
+        }
+    }
+    /// Perceive the watchpoints which are due, and mark the clusters
+    /// watching those which have changed dirty.
+    pub fn perceive_due(&mut self, manager: &Manager) {
+        let now = Instant::now();
+        for entry in self.entries.iter_mut().filter(|e| e.due <= now) {
+            let interval = entry.poll_interval as u64;
+            match manager.perceive(&entry.watchpoint) {
+                Ok(cause) => {
+                    entry.failures = 0;
+                    entry.due = now + Duration::from_secs(interval);
+                    if let Some(cause) = cause {
+                        for cluster in entry.clusters.iter() {
+                            self.dirty.entry(cluster.clone()).or_insert(vec![]).push(cause.clone());
+                        }
+                    }
+                },
+                Err(e) => {
+                    entry.failures += 1;
+                    let backoff = cmp::max(interval,
+                                           cmp::min(interval << cmp::min(entry.failures, 12),
+                                                    MAX_BACKOFF));
+                    warn!("{} watchpoint, retrying in {}s: {}", entry.watchpoint.kind(), backoff, e);
+                    entry.due = now + Duration::from_secs(backoff);
+                },
+            }
+        }
+    }
+    /// Mark the clusters dirty which have been scheduled to run at any
+    /// minute since the last call, once however many minutes it was.
+    pub fn tick(&mut self) {
+        let now = current_minute();
+        let since = cmp::max(self.ticked, now - MAX_CATCH_UP);
+        self.ticked = now;
+        for &(ref cluster, ref schedule) in self.crons.iter() {
+            let mut minute = now;
+            while minute > since {
+                if schedule.matches(&time::at(time::Timespec::new(minute * 60, 0))) {
+                    let cause = Cause::Scheduled { at: minute as u64 * 60 * 1000 };
+                    self.dirty.entry(cluster.clone()).or_insert(vec![]).push(cause);
+                    break;
+                }
+                minute -= 1;
+            }
+        }
+    }
+    /// Causes to run the cluster for, which are forgotten by taking them.
+    pub fn take_dirty(&mut self, cluster: &str) -> Vec<Cause> {
+        self.dirty.remove(cluster).unwrap_or(vec![])
+    }
+}
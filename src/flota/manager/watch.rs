@@ -1,57 +1,125 @@
+use crypto::digest::Digest;
+use crypto::md5::Md5;
 use git2::{Direction, ErrorCode, Repository};
+use glob::Pattern;
+use hyper::Client;
+use hyper::header::Headers;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use serde_json;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::prelude::*;
 use std::path::Path;
+use std::time::Duration;
+use ::exec::session::{ExecOptions, NullSink, Session};
+use ::exec::session::local::SessLocal;
 use ::flota::Cypherable;
 use ::flota::config::cluster::watchpoint::WatchPoint;
+use ::util::errors::*;
 use ::util::md5sum::calc_md5;
 use ::util::url::Url;
 
-#[derive(Debug, Clone)]
+// Seconds to wait on reading from or writing to a watched http server.
+const HTTP_TIMEOUT: u64 = 30;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum WatchPointPerceptionValue {
     Git {
         ref_commit_ids: Vec<(String, Vec<u8>)>,
     },
     File {
         checksum: Vec<u8>,
+    },
+    Dir {
+        digest: Vec<u8>,
+        // those of the files matched, by their paths relative to the dir.
+        files: BTreeMap<String, Vec<u8>>,
+    },
+    // digest of the normalized stdout.
+    Command {
+        checksum: Vec<u8>,
+    },
+    // only one of etag, last_modified and checksum is there, in the
+    // order of preference.
+    Http {
+        status: u16,
+        etag: Option<String>,
+        last_modified: Option<String>,
+        checksum: Option<Vec<u8>>,
+    },
+}
+
+impl WatchPointPerceptionValue {
+    /// Paths which differ from the previous perception, for those which
+    /// have the notion.
+    pub fn changed_since(&self, previous: &Self) -> Vec<String> {
+        match (self, previous) {
+            (&WatchPointPerceptionValue::Dir { files: ref now, .. },
+             &WatchPointPerceptionValue::Dir { files: ref before, .. }) => {
+                let mut changed = now.iter()
+                                     .filter(|&(path, digest)| before.get(path) != Some(digest))
+                                     .map(|(path, _)| path.clone())
+                                     .collect::<Vec<_>>();
+                changed.extend(before.keys().filter(|path| !now.contains_key(*path)).cloned());
+                changed.sort();
+                changed
+            },
+            _ => vec![],
+        }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WatchPointPerception {
     pub value: WatchPointPerceptionValue,
 }
 
 impl Cypherable for WatchPointPerception {
-    fn cypher_ident(&self) -> String {
-        format!("WatchPointPerception {{ value: '{:?}' }}",
-                self.value)
+    fn cypher_props(&self) -> Vec<(&'static str, String)> {
+        // JSON so that it can be read back as it was.
+        vec![ ("value", serde_json::to_string(&self.value).unwrap()) ]
     }
 }
 
 impl WatchPointPerception {
-    pub fn new(watchpoint: &WatchPoint) -> Self {
-        let perception = Self::perceive(watchpoint);
-        WatchPointPerception {
+    /// Fails if what is watched cannot be reached at the moment, in which
+    /// case it is neither changed nor unchanged.
+    pub fn new(watchpoint: &WatchPoint) -> Result<Self> {
+        let perception = try!(Self::perceive(watchpoint));
+        Ok(WatchPointPerception {
             value: perception,
-        }
+        })
     }
     fn perceive_git(uri: &Url, remote: &str, refs: &Vec<String>,
-                    checkout_dir: &Path) -> WatchPointPerceptionValue {
+                    checkout_dir: &Path) -> Result<WatchPointPerceptionValue> {
         let url = uri.as_str();
         let repo = match Repository::clone(url, checkout_dir) {
             Ok(repo) => { repo },
             Err(ref e) if e.code() == ErrorCode::Exists => {
                 // XXX: re-clone if it's broken
-                Repository::open(checkout_dir).expect(
-                    format!("failed to open {:?}", checkout_dir).as_str())
+                match Repository::open(checkout_dir) {
+                    Ok(repo) => repo,
+                    Err(e) => {
+                        return Err(format!("failed to open {}: {}", checkout_dir.display(), e)
+                                       .into());
+                    },
+                }
             },
-            Err(e) => panic!("failed to clone: {}", e),
+            Err(e) => return Err(format!("failed to clone {}: {}", url, e).into()),
+        };
+        let mut rem = match repo.find_remote(remote) {
+            Ok(rem) => rem,
+            Err(e) => return Err(format!("no remote {} in {}: {}", remote, url, e).into()),
         };
-        let mut rem = repo.find_remote(remote).unwrap();
-        rem.connect(Direction::Fetch).expect(format!(
-                "failed to connect to {}", remote).as_str());
-        let ref_commit_ids = rem
-            .list()
-            .unwrap()
+        if let Err(e) = rem.connect(Direction::Fetch) {
+            return Err(format!("failed to connect to {}: {}", remote, e).into());
+        }
+        let heads = match rem.list() {
+            Ok(heads) => heads,
+            Err(e) => return Err(format!("failed to list refs of {}: {}", remote, e).into()),
+        };
+        let ref_commit_ids = heads
             .iter()
             .map(|head| (head.name().to_owned(), head.oid().as_bytes().to_vec()))
             .filter(|r1| {
@@ -62,16 +130,145 @@ impl WatchPointPerception {
                 }
             })
             .collect::<Vec<_>>();
-        WatchPointPerceptionValue::Git {
+        Ok(WatchPointPerceptionValue::Git {
             ref_commit_ids: ref_commit_ids,
+        })
+    }
+    fn perceive_file(path: &Path) -> Result<WatchPointPerceptionValue> {
+        Ok(WatchPointPerceptionValue::File {
+            checksum: try!(calc_md5(path)).as_bytes().to_vec(),
+        })
+    }
+    // Merkle-style, i.e. digest of a directory is over names and digests
+    // of its children, so that a change anywhere in the tree changes all
+    // the digests up to the root. None if nothing in it is matched.
+    fn digest_dir(root: &Path,
+                  rel: &Path,
+                  include: &[Pattern],
+                  exclude: &[Pattern],
+                  files: &mut BTreeMap<String, Vec<u8>>)
+                  -> Result<Option<String>> {
+        let mut names = Vec::new();
+        for entry in try!(fs::read_dir(root.join(rel))) {
+            names.push(try!(entry).file_name().to_string_lossy().into_owned());
         }
+        names.sort();
+        let mut hasher = Md5::new();
+        let mut matched = false;
+        for name in names.iter() {
+            let rel = rel.join(name);
+            if exclude.iter().any(|p| p.matches_path(&rel)) {
+                continue;
+            }
+            let full = root.join(&rel);
+            // symlinked directories are not followed, lest they loop.
+            let digest = if try!(fs::symlink_metadata(&full)).is_dir() {
+                match try!(Self::digest_dir(root, &rel, include, exclude, files)) {
+                    Some(digest) => format!("d {}", digest),
+                    None => continue,
+                }
+            } else if include.iter().any(|p| p.matches_path(&rel)) && full.is_file() {
+                let digest = try!(calc_md5(&full));
+                files.insert(rel.to_string_lossy().into_owned(), digest.as_bytes().to_vec());
+                format!("f {}", digest)
+            } else {
+                continue;
+            };
+            hasher.input_str(&format!("{}\0{}\n", name, digest));
+            matched = true;
+        }
+        Ok(if matched { Some(hasher.result_str()) } else { None })
     }
-    fn perceive_file(path: &Path) -> WatchPointPerceptionValue {
-        WatchPointPerceptionValue::File {
-            checksum: calc_md5(path).unwrap().as_bytes().to_vec(),
+    fn perceive_dir(path: &Path, include: &[String], exclude: &[String])
+                    -> Result<WatchPointPerceptionValue> {
+        let compile = |globs: &[String]| {
+            globs.iter().filter_map(|g| Pattern::new(g).ok()).collect::<Vec<_>>()
+        };
+        let mut files = BTreeMap::new();
+        let digest = try!(Self::digest_dir(path, Path::new(""), &compile(include),
+                                           &compile(exclude), &mut files));
+        Ok(WatchPointPerceptionValue::Dir {
+            // that of nothing, if nothing is matched.
+            digest: digest.unwrap_or(String::new()).into_bytes(),
+            files: files,
+        })
+    }
+    // Output of a command is compared as is, apart from differences in
+    // whitespace at the ends of lines and of the whole.
+    fn normalize(output: &str) -> String {
+        output.lines()
+              .map(|line| line.trim_right())
+              .collect::<Vec<_>>()
+              .join("\n")
+              .trim_matches('\n')
+              .to_owned()
+    }
+    fn perceive_command(command: &str, timeout: u32) -> Result<WatchPointPerceptionValue> {
+        let mut opts = ExecOptions::default();
+        opts.timeout = Some(timeout);
+        let output = try!(SessLocal {}.exec_streamed(command, &opts, &mut NullSink));
+        // output of a failed command tells nothing of what is watched.
+        if output.status != Some(0) {
+            return Err(format!("{} exited with {:?}: {}",
+                               command, output.status, output.stderr.unwrap_or(String::new()).trim())
+                           .into());
+        }
+        let mut hasher = Md5::new();
+        hasher.input_str(&Self::normalize(&output.stdout.unwrap_or(String::new())));
+        Ok(WatchPointPerceptionValue::Command {
+            checksum: hasher.result_str().into_bytes(),
+        })
+    }
+    fn perceive_http(url: &Url, method: &str, headers: &BTreeMap<String, String>)
+                     -> Result<WatchPointPerceptionValue> {
+        let mut req_headers = Headers::new();
+        for (name, value) in headers.iter() {
+            req_headers.set_raw(name.clone(), vec![value.as_bytes().to_vec()]);
+        }
+        let method = if method == "HEAD" { Method::Head } else { Method::Get };
+        // lest a server which never answers hold up the cycle.
+        let mut client = Client::new();
+        client.set_read_timeout(Some(Duration::from_secs(HTTP_TIMEOUT)));
+        client.set_write_timeout(Some(Duration::from_secs(HTTP_TIMEOUT)));
+        let mut res = match client.request(method.clone(), url.as_str())
+                                  .headers(req_headers)
+                                  .send() {
+            Ok(res) => res,
+            Err(e) => return Err(format!("failed to request {}: {}", url.as_str(), e).into()),
+        };
+        // an error page is no perception of the resource, but not modified is.
+        if !res.status.is_success() && res.status != StatusCode::NotModified {
+            return Err(format!("{} responded {}", url.as_str(), res.status).into());
         }
+        let (etag, last_modified) = {
+            let header = |name: &str| {
+                res.headers
+                   .get_raw(name)
+                   .and_then(|vals| vals.first())
+                   .map(|val| String::from_utf8_lossy(val).into_owned())
+            };
+            let etag = header("ETag");
+            let last_modified = if etag.is_none() { header("Last-Modified") } else { None };
+            (etag, last_modified)
+        };
+        let status = res.status.to_u16();
+        let checksum = if etag.is_none() && last_modified.is_none() && method != Method::Head {
+            let mut body = Vec::new();
+            try!(res.read_to_end(&mut body));
+            let mut hasher = Md5::new();
+            hasher.input(&body);
+            Some(hasher.result_str().into_bytes())
+        } else {
+            None
+        };
+        Ok(WatchPointPerceptionValue::Http {
+            status: status,
+            etag: etag,
+            last_modified: last_modified,
+            checksum: checksum,
+        })
     }
-    pub fn perceive(watchpoint: &WatchPoint) -> WatchPointPerceptionValue {
+    pub fn perceive(watchpoint: &WatchPoint) -> Result<WatchPointPerceptionValue> {
         match *watchpoint {
             WatchPoint::Git {
                 ref uri,
@@ -85,7 +282,135 @@ impl WatchPointPerception {
                 ref path,
             } => {
                 Self::perceive_file(path)
-            }
+            },
+            WatchPoint::Dir {
+                ref path,
+                ref include,
+                ref exclude,
+            } => {
+                Self::perceive_dir(path, include, exclude)
+            },
+            WatchPoint::Command {
+                ref command,
+                timeout,
+            } => {
+                Self::perceive_command(command, timeout)
+            },
+            WatchPoint::Http {
+                ref url,
+                ref method,
+                ref headers,
+            } => {
+                Self::perceive_http(url, method, headers)
+            },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use super::{WatchPointPerception, WatchPointPerceptionValue};
+    use ::flota::config::cluster::watchpoint::WatchPoint;
+    use ::util::url::Url;
+
+    // Serves the response once, to whatever is requested.
+    fn serve_once(response: &'static str) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf);
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        Url::parse(&format!("http://{}/repodata/repomd.xml", addr)).unwrap()
+    }
+
+    fn perceive(url: Url) -> WatchPointPerceptionValue {
+        WatchPointPerception::perceive(&WatchPoint::Http {
+            url: url,
+            method: "GET".to_string(),
+            headers: BTreeMap::new(),
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_perceive_http() {
+        let url = serve_once("HTTP/1.1 200 OK\r\nETag: \"abc\"\r\nContent-Length: 2\r\n\
+                              Connection: close\r\n\r\nhi");
+        assert_eq!(perceive(url), WatchPointPerceptionValue::Http {
+            status: 200,
+            etag: Some("\"abc\"".to_string()),
+            last_modified: None,
+            checksum: None,
+        });
+        let url = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\
+                              Connection: close\r\n\r\nhi");
+        assert_eq!(perceive(url), WatchPointPerceptionValue::Http {
+            status: 200,
+            etag: None,
+            last_modified: None,
+            checksum: Some("49f68a5c8493ec2c0bf489821c21fc3b".as_bytes().to_vec()),
+        });
+        let url = serve_once("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\
+                              Connection: close\r\n\r\n");
+        assert!(WatchPointPerception::perceive(&WatchPoint::Http {
+            url: url,
+            method: "GET".to_string(),
+            headers: BTreeMap::new(),
+        }).is_err());
+    }
+
+    #[test]
+    fn test_perceive_command() {
+        let perceive = |command: &str, timeout: u32| {
+            WatchPointPerception::perceive(&WatchPoint::Command {
+                command: command.to_string(),
+                timeout: timeout,
+            })
+        };
+        assert_eq!(perceive("printf 'libvirt-1.2.17  \\r\\n\\n'", 5).unwrap(),
+                   WatchPointPerceptionValue::Command {
+                       checksum: "2ad1ecb4ae94009afef0292d46cb44a3".as_bytes().to_vec(),
+                   });
+        assert!(perceive("printf 'libvirt-1.2.17'; exit 1", 5).is_err());
+        assert!(perceive("sleep 5", 1).is_err());
+    }
+
+    #[test]
+    fn test_perceive_dir() {
+        let root = env::temp_dir().join("flota-test-perceive-dir");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sub/.git")).unwrap();
+        let write = |rel: &str, content: &str| {
+            File::create(root.join(rel)).unwrap().write_all(content.as_bytes()).unwrap();
+        };
+        write("a.toml", "a");
+        write("sub/b.toml", "b");
+        write("sub/c.log", "c");
+        write("sub/.git/HEAD", "ref");
+        let watchpoint = WatchPoint::Dir {
+            path: root.clone(),
+            include: vec!["**/*.toml".to_string()],
+            exclude: vec!["**/.git".to_string()],
+        };
+        let before = WatchPointPerception::perceive(&watchpoint).unwrap();
+        write("sub/c.log", "changed but not included");
+        write("sub/.git/HEAD", "changed but excluded");
+        assert_eq!(WatchPointPerception::perceive(&watchpoint).unwrap(), before);
+        write("sub/b.toml", "changed");
+        write("sub/d.toml", "added");
+        fs::remove_file(root.join("a.toml")).unwrap();
+        let after = WatchPointPerception::perceive(&watchpoint).unwrap();
+        assert!(after != before);
+        assert_eq!(after.changed_since(&before), vec!["a.toml", "sub/b.toml", "sub/d.toml"]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
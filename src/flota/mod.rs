@@ -2,33 +2,64 @@ use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 use std::intrinsics;
 
+// Prepended to the labels of our nodes, as the database may well be
+// shared with others who have their own `Cluster` or `Host`.
+pub const LABEL_PREFIX: &'static str = "Flota_";
+
 pub trait Cypherable {
     fn label(&self) -> String {
         unsafe {
-            intrinsics::type_name::<Self>()
-                .split(':').last().unwrap()
-                .to_string()
+            format!("{}{}",
+                    LABEL_PREFIX,
+                    intrinsics::type_name::<Self>().split(':').last().unwrap())
         }
     }
-    fn cypher_ident(&self) -> String;
+    /// Node properties. Values are sent as query parameters and are
+    /// never spliced into the query text, so they may contain anything.
+    fn cypher_props(&self) -> Vec<(&'static str, String)>;
+    /// Node pattern whose property values refer to parameters named
+    /// `<prefix>_<key>`, e.g. "Flota_Cluster { name: {p_name} }".
+    fn cypher_pattern(&self, prefix: &str) -> String {
+        format!("{} {{ {} }}",
+                self.label(),
+                self.cypher_props()
+                    .iter()
+                    .map(|&(key, _)| format!("{k}: {{{p}_{k}}}", k = key, p = prefix))
+                    .collect::<Vec<_>>()
+                    .join(", "))
+    }
+}
+
+// Build a Statement out of the query and the properties of the nodes
+// its patterns refer to with the given prefixes.
+macro_rules! cypher_statement {
+    ( $query:expr, $( $prefix:expr => $node:expr ),* ) => {{
+        let mut statement = ::rusted_cypher::Statement::new(&*$query);
+        $(
+            for (key, val) in $node.cypher_props() {
+                try!(statement.add_param(format!("{}_{}", $prefix, key).as_str(), &val));
+            }
+        )*
+        statement
+    }}
 }
 
 macro_rules! save_child_rel {
     ( $tx:expr, $parent:expr, $child:expr, $rel:tt ) => {{
-        $tx.exec(
+        $tx.exec(cypher_statement!(
             format!("MERGE (p: {}) MERGE (c: {})
                      MERGE (p)-[:{}]-(c)",
-                    $parent.cypher_ident(),
-                    $child.cypher_ident(),
-                    $rel,
-            ).as_ref()
-        )
+                    $parent.cypher_pattern("p"),
+                    $child.cypher_pattern("c"),
+                    $rel),
+            "p" => $parent, "c" => $child
+        ))
     }}
 }
 
 macro_rules! save_child_ll {
     ( $tx:expr, $parent:expr, $child:expr, $rel:tt ) => {{
-        try!($tx.exec(
+        try!($tx.exec(cypher_statement!(
             format!("MERGE (p: {p})
                      MERGE (c: {c})
                      MERGE (c)-[ptr:{rel}]->(p)
@@ -37,38 +68,40 @@ macro_rules! save_child_ll {
                      WHERE NOT id(tail) = id(c)
                      DELETE ptr
                      MERGE (c)-[:PREV]->(tail)",
-                    p = $parent.cypher_ident(),
-                    c = $child.cypher_ident(),
+                    p = $parent.cypher_pattern("p"),
+                    c = $child.cypher_pattern("c"),
                     rel = $rel,
-                    tail = $child.label()
-            ).as_ref()
-        ));
-        $tx.exec(
+                    tail = $child.label()),
+            "p" => $parent, "c" => $child
+        )));
+        $tx.exec(cypher_statement!(
             format!("MATCH (p: {p})
                      MATCH (c: {c})
                      MERGE (p)-[:TAIL]->(c)",
-                    p = $parent.cypher_ident(),
-                    c = $child.cypher_ident()
-            ).as_ref()
-        ).map(|_| true)
+                    p = $parent.cypher_pattern("p"),
+                    c = $child.cypher_pattern("c")),
+            "p" => $parent, "c" => $child
+        )).map(|_| true)
     }}
 }
 
 macro_rules! is_tail {
-    ( $parent:expr, $child:expr ) => {{
-        let graph = GraphClient::connect(::NEO4J_ENDPOINT).unwrap();
-        graph.cypher().exec(
+    ( $graph:expr, $parent:expr, $child:expr ) => {{
+        $graph.cypher().exec(cypher_statement!(
             format!("MATCH (c: {})<-[:TAIL]-(p: {}) RETURN c",
-                   $child.cypher_ident(),
-                   $parent.cypher_ident(),
-            ).as_ref()
-        ).map(|r| r.rows().count() > 0)
+                   $child.cypher_pattern("c"),
+                   $parent.cypher_pattern("p")),
+            "c" => $child, "p" => $parent
+        )).map(|r| r.rows().count() > 0)
     }}
 }
 
 pub mod config;
 pub mod entity;
 pub mod manager;
+pub mod runlog;
+pub mod spool;
+pub mod store;
 pub mod test;
 
 pub fn hash<T: Hash>(t: &T) -> u64 {
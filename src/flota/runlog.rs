@@ -0,0 +1,187 @@
+use serde_json;
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use ::consts::*;
+use ::exec::session::OutputSink;
+use ::util::errors::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RunLogEvent {
+    Start {
+        host: String,
+        command: String,
+    },
+    Stdout {
+        data: String,
+    },
+    Stderr {
+        data: String,
+    },
+    End {
+        status: Option<i32>,
+    },
+}
+
+impl RunLogEvent {
+    /// Event name as sent over SSE.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RunLogEvent::Start { .. } => "start",
+            RunLogEvent::Stdout { .. } => "stdout",
+            RunLogEvent::Stderr { .. } => "stderr",
+            RunLogEvent::End { .. } => "end",
+        }
+    }
+}
+
+// Output of the execs of each run, written as it arrives so that the api
+// can stream it while the run is still in progress:
+//
+//   <root>/<history id>.jsonl
+//   <root>/<history id>.consoles.json   where serial console logs of the
+//                                      hosts were at as the run started
+//
+// Execs of a run are serial, so events of one exec are never interleaved
+// with those of another.
+#[derive(Debug, Clone)]
+pub struct RunLogs {
+    root: PathBuf,
+}
+
+impl RunLogs {
+    pub fn new(root: &Path) -> Result<Self> {
+        try!(fs::create_dir_all(root));
+        Ok(RunLogs {
+            root: root.to_path_buf(),
+        })
+    }
+    pub fn open() -> Result<Self> {
+        Self::new(&DATA_DIR.join("logs"))
+    }
+    fn path(&self, history_id: u64) -> PathBuf {
+        self.root.join(format!("{}.jsonl", history_id))
+    }
+    fn consoles_path(&self, history_id: u64) -> PathBuf {
+        self.root.join(format!("{}.consoles.json", history_id))
+    }
+    /// Remember the offsets serial console logs of the hosts are at, by
+    /// hostname, so that a run never shows what an earlier one left.
+    pub fn mark_consoles(&self, history_id: u64, offsets: &BTreeMap<String, u64>) -> Result<()> {
+        let mut f = try!(File::create(self.consoles_path(history_id)));
+        try!(f.write_all(try!(serde_json::to_string(offsets)).as_bytes()));
+        Ok(())
+    }
+    /// Offset the console log of the host was at as the run started, none
+    /// if it has not started yet.
+    pub fn console_offset(&self, history_id: u64, hostname: &str) -> Result<Option<u64>> {
+        let path = self.consoles_path(history_id);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut content = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut content));
+        let offsets: BTreeMap<String, u64> = try!(serde_json::from_str(&content));
+        Ok(Some(offsets.get(hostname).cloned().unwrap_or(0)))
+    }
+    /// Log of an exec about to start.
+    pub fn exec(&self, history_id: u64, host: &str, command: &str) -> Result<ExecLog> {
+        let mut log = ExecLog {
+            file: try!(OpenOptions::new().append(true).create(true).open(self.path(history_id))),
+        };
+        log.append(&RunLogEvent::Start {
+            host: host.to_string(),
+            command: command.to_string(),
+        });
+        Ok(log)
+    }
+    /// Events written since `offset`, along with the offset to resume from.
+    pub fn read_from(&self, history_id: u64, offset: u64) -> Result<(Vec<RunLogEvent>, u64)> {
+        let path = self.path(history_id);
+        if !path.exists() {
+            return Ok((vec![], offset));
+        }
+        let mut f = try!(File::open(path));
+        try!(f.seek(SeekFrom::Start(offset)));
+        let mut buf = Vec::new();
+        try!(f.read_to_end(&mut buf));
+        // a line still being written is left for the next read.
+        let complete = buf.iter().rposition(|&b| b == b'\n').map(|i| i + 1).unwrap_or(0);
+        let mut events = Vec::new();
+        for line in String::from_utf8_lossy(&buf[..complete]).lines().filter(|l| !l.is_empty()) {
+            events.push(try!(serde_json::from_str(line)));
+        }
+        Ok((events, offset + complete as u64))
+    }
+    pub fn delete(&self, history_id: u64) -> Result<()> {
+        for path in vec![self.path(history_id), self.consoles_path(history_id)] {
+            if path.exists() {
+                try!(fs::remove_file(path));
+            }
+        }
+        Ok(())
+    }
+    pub fn clear(&self) -> Result<()> {
+        if self.root.exists() {
+            try!(fs::remove_dir_all(&self.root));
+        }
+        try!(fs::create_dir_all(&self.root));
+        Ok(())
+    }
+}
+
+pub struct ExecLog {
+    file: File,
+}
+
+impl ExecLog {
+    fn append(&mut self, event: &RunLogEvent) {
+        let mut line = serde_json::to_string(event).unwrap();
+        line.push('\n');
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            warn!("failed to write run log: {}", e);
+        }
+    }
+    pub fn end(mut self, status: Option<i32>) {
+        self.append(&RunLogEvent::End { status: status });
+    }
+}
+
+impl OutputSink for ExecLog {
+    fn stdout(&mut self, data: &str) {
+        self.append(&RunLogEvent::Stdout { data: data.to_string() });
+    }
+    fn stderr(&mut self, data: &str) {
+        self.append(&RunLogEvent::Stderr { data: data.to_string() });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use ::exec::session::OutputSink;
+    use super::{RunLogEvent, RunLogs};
+
+    #[test]
+    fn test_read_from_offset() {
+        let root = env::temp_dir().join(".test_run_logs");
+        let _ = fs::remove_dir_all(&root);
+        let logs = RunLogs::new(&root).expect("failed to create run logs");
+        let mut log = logs.exec(1, "host", "echo hello").unwrap();
+        log.stdout("hello\n");
+        let (events, offset) = logs.read_from(1, 0).unwrap();
+        assert_eq!(events.len(), 2);
+        log.end(Some(0));
+        let (events, _) = logs.read_from(1, offset).unwrap();
+        assert_eq!(events.len(), 1);
+        match events[0] {
+            RunLogEvent::End { status } => assert_eq!(status, Some(0)),
+            _ => panic!("unexpected event"),
+        }
+        fs::remove_dir_all(&root).expect("failed to remove run logs");
+    }
+}
@@ -0,0 +1,158 @@
+use serde_json;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use ::consts::*;
+use ::flota::store::History;
+use ::util::errors::*;
+
+// Requests from the api process to the main loop. The two are separate
+// processes, so requests are dropped as files which the main loop picks
+// up on its next cycle:
+//
+//   <root>/run/<cluster id>/<history id>.json   run waiting to be started
+//   <root>/cancel/<history id>                  run to be cancelled
+//   <root>/reload                               config to be read again
+#[derive(Debug, Clone)]
+pub struct Spool {
+    root: PathBuf,
+}
+
+impl Spool {
+    pub fn new(root: &Path) -> Result<Self> {
+        for sub in ["run", "cancel"].iter() {
+            try!(fs::create_dir_all(root.join(sub)));
+        }
+        Ok(Spool {
+            root: root.to_path_buf(),
+        })
+    }
+    pub fn open() -> Result<Self> {
+        Self::new(&DATA_DIR.join("spool"))
+    }
+    fn run_dir(&self, cluster_id: u64) -> PathBuf {
+        self.root.join("run").join(cluster_id.to_string())
+    }
+    fn cancel_path(&self, history_id: u64) -> PathBuf {
+        self.root.join("cancel").join(history_id.to_string())
+    }
+    /// Ask for the (pending) history to be run.
+    pub fn request_run(&self, history: &History) -> Result<()> {
+        let dir = self.run_dir(history.cluster_id);
+        try!(fs::create_dir_all(&dir));
+        let path = dir.join(format!("{}.json", history.id));
+        // write then rename so that the main loop never sees a partial file.
+        let tmp = path.with_extension("json.tmp");
+        {
+            let mut f = try!(File::create(&tmp));
+            try!(f.write_all(try!(serde_json::to_string(history)).as_bytes()));
+        }
+        try!(fs::rename(&tmp, &path));
+        Ok(())
+    }
+    /// Take the oldest run requested for the cluster, if any.
+    pub fn take_run(&self, cluster_id: u64) -> Result<Option<History>> {
+        let dir = self.run_dir(cluster_id);
+        if !dir.exists() {
+            return Ok(None);
+        }
+        let mut paths = Vec::new();
+        for entry in try!(fs::read_dir(&dir)) {
+            let path = try!(entry).path();
+            if path.extension().map(|ext| ext == "json") == Some(true) {
+                paths.push(path);
+            }
+        }
+        // history ids are timestamps, thus the smallest is the oldest.
+        paths.sort_by_key(|p| {
+            p.file_stem()
+             .and_then(|s| s.to_str())
+             .and_then(|s| s.parse::<u64>().ok())
+             .unwrap_or(0)
+        });
+        match paths.first() {
+            Some(path) => {
+                let mut buf = String::new();
+                try!(try!(File::open(path)).read_to_string(&mut buf));
+                try!(fs::remove_file(path));
+                Ok(Some(try!(serde_json::from_str(&buf))))
+            },
+            None => Ok(None),
+        }
+    }
+    /// Ask for the config to be read again, e.g. as watchpoints have been
+    /// added to the store.
+    pub fn request_reload(&self) -> Result<()> {
+        try!(File::create(self.root.join("reload")));
+        Ok(())
+    }
+    /// True once for each request to reload.
+    pub fn take_reload(&self) -> bool {
+        fs::remove_file(self.root.join("reload")).is_ok()
+    }
+    pub fn request_cancel(&self, history_id: u64) -> Result<()> {
+        try!(File::create(self.cancel_path(history_id)));
+        Ok(())
+    }
+    pub fn is_cancelled(&self, history_id: u64) -> bool {
+        self.cancel_path(history_id).exists()
+    }
+    /// Drop every request, whether to run, to cancel or to reload.
+    pub fn clear(&self) -> Result<()> {
+        let _ = self.take_reload();
+        for sub in ["run", "cancel"].iter() {
+            let dir = self.root.join(sub);
+            if dir.exists() {
+                try!(fs::remove_dir_all(&dir));
+            }
+            try!(fs::create_dir_all(&dir));
+        }
+        Ok(())
+    }
+    /// Forget the cancel request once the run has been wound up.
+    pub fn clear_cancel(&self, history_id: u64) -> Result<()> {
+        let path = self.cancel_path(history_id);
+        if path.exists() {
+            try!(fs::remove_file(path));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use ::flota::store::{ClusterRecord, History};
+    use super::Spool;
+
+    #[test]
+    fn test_take_run_oldest_first() {
+        let root = env::temp_dir().join(".test_spool_take_run");
+        let _ = fs::remove_dir_all(&root);
+        let spool = Spool::new(&root).expect("failed to create spool");
+        let cluster = ClusterRecord {
+            id: 1,
+            name: "test".to_string(),
+            config_id: 2,
+            watchpoints: vec![],
+            hosts: vec![],
+        };
+        let mut h1 = History::requested(&cluster, "test");
+        let mut h2 = h1.clone();
+        h1.id = 100;
+        h2.id = 200;
+        spool.request_run(&h2).unwrap();
+        spool.request_run(&h1).unwrap();
+        assert_eq!(spool.take_run(1).unwrap().map(|h| h.id), Some(100));
+        assert_eq!(spool.take_run(1).unwrap().map(|h| h.id), Some(200));
+        assert!(spool.take_run(1).unwrap().is_none());
+        assert!(!spool.is_cancelled(100));
+        spool.request_cancel(100).unwrap();
+        assert!(spool.is_cancelled(100));
+        spool.clear_cancel(100).unwrap();
+        assert!(!spool.is_cancelled(100));
+        fs::remove_dir_all(&root).expect("failed to remove spool");
+    }
+}
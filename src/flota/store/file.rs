@@ -0,0 +1,259 @@
+use serde::{Deserialize, Serialize};
+use serde_json;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use ::exec::ExecResult;
+use ::flota::hash;
+use ::flota::config::Exec;
+use ::flota::config::cluster::Cluster;
+use ::flota::config::cluster::watchpoint::WatchPoint;
+use ::flota::manager::watch::WatchPointPerception;
+use ::flota::test::Cause;
+use ::util::errors::*;
+use super::{ClusterRecord, History, Store};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecResultRecord {
+    pub result: ExecResult,
+    pub causes: Vec<Cause>,
+}
+
+// Embedded store which needs nothing but a local directory:
+//
+//   <root>/clusters/<cluster id>.json
+//   <root>/histories/<cluster id>/<history id>.json
+//   <root>/perceptions/<watchpoint id>.jsonl
+//   <root>/results/<exec id>.jsonl
+//   <root>/watchpoints/<cluster id>.jsonl     added through the api
+//
+// Perceptions and results are append-only JSON lines, the last line
+// being the tail.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    root: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(root: &Path) -> Result<Self> {
+        for sub in ["clusters", "histories", "perceptions", "results", "watchpoints"].iter() {
+            try!(fs::create_dir_all(root.join(sub)));
+        }
+        Ok(FileStore {
+            root: root.to_path_buf(),
+        })
+    }
+    fn cluster_path(&self, id: u64) -> PathBuf {
+        self.root.join("clusters").join(format!("{}.json", id))
+    }
+    fn histories_dir(&self, cluster_id: u64) -> PathBuf {
+        self.root.join("histories").join(cluster_id.to_string())
+    }
+    fn perceptions_path(&self, watchpoint: &WatchPoint) -> PathBuf {
+        self.root.join("perceptions").join(format!("{}.jsonl", hash(watchpoint)))
+    }
+    fn added_watchpoints_path(&self, cluster_id: u64) -> PathBuf {
+        self.root.join("watchpoints").join(format!("{}.jsonl", cluster_id))
+    }
+    fn results_path(&self, exec: &Exec) -> PathBuf {
+        self.root.join("results").join(format!("{}.jsonl", hash(exec)))
+    }
+    // write then rename so that readers never see a partial file.
+    fn write_json<T: Serialize>(path: &Path, record: &T) -> Result<()> {
+        let tmp = path.with_extension("json.tmp");
+        {
+            let mut f = try!(File::create(&tmp));
+            try!(f.write_all(try!(serde_json::to_string(record)).as_bytes()));
+        }
+        try!(fs::rename(&tmp, path));
+        Ok(())
+    }
+    fn read_json_dir<T: Deserialize>(dir: &Path) -> Result<Vec<T>> {
+        let mut records = Vec::new();
+        if !dir.exists() {
+            return Ok(records);
+        }
+        for entry in try!(fs::read_dir(dir)) {
+            let path = try!(entry).path();
+            if path.extension().map(|ext| ext == "json") != Some(true) {
+                continue;
+            }
+            let mut buf = String::new();
+            try!(try!(File::open(&path)).read_to_string(&mut buf));
+            records.push(try!(serde_json::from_str(&buf)));
+        }
+        Ok(records)
+    }
+    fn append_line<T: Serialize>(path: &Path, record: &T) -> Result<()> {
+        let mut line = try!(serde_json::to_string(record));
+        line.push('\n');
+        let mut f = try!(OpenOptions::new().append(true).create(true).open(path));
+        try!(f.write_all(line.as_bytes()));
+        Ok(())
+    }
+    // oldest first
+    fn read_lines<T: Deserialize>(path: &Path) -> Result<Vec<T>> {
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        let mut buf = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut buf));
+        let mut records = Vec::new();
+        for line in buf.lines().filter(|l| !l.is_empty()) {
+            records.push(try!(serde_json::from_str(line)));
+        }
+        Ok(records)
+    }
+}
+
+impl Store for FileStore {
+    fn save_cluster(&self, cluster: &Cluster) -> Result<()> {
+        let record = ClusterRecord::new(cluster);
+        Self::write_json(&self.cluster_path(record.id), &record)
+    }
+    fn is_first_run(&self, cluster: &Cluster) -> Result<bool> {
+        // same criterion as the graph store, i.e. cluster-level execs only.
+        for exec in cluster.pre_tests.iter()
+            .chain(cluster.tests.iter())
+            .chain(cluster.post_tests.iter()) {
+            let recorded = fs::metadata(self.results_path(exec))
+                .map(|m| m.len() > 0)
+                .unwrap_or(false);
+            if recorded {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+    fn append_perception(&self,
+                         watchpoint: &WatchPoint,
+                         perception: &WatchPointPerception)
+                         -> Result<()> {
+        Self::append_line(&self.perceptions_path(watchpoint), perception)
+    }
+    fn is_tail_perception(&self,
+                          watchpoint: &WatchPoint,
+                          perception: &WatchPointPerception)
+                          -> Result<bool> {
+        let history: Vec<WatchPointPerception> =
+            try!(Self::read_lines(&self.perceptions_path(watchpoint)));
+        Ok(history.last().map(|tail| tail.value == perception.value).unwrap_or(false))
+    }
+    fn perceptions(&self, watchpoint: &WatchPoint) -> Result<Vec<WatchPointPerception>> {
+        let mut history: Vec<WatchPointPerception> =
+            try!(Self::read_lines(&self.perceptions_path(watchpoint)));
+        history.reverse();
+        Ok(history)
+    }
+    fn append_exec_result(&self,
+                          exec: &Exec,
+                          result: &ExecResult,
+                          causes: &Vec<Cause>)
+                          -> Result<()> {
+        Self::append_line(&self.results_path(exec), &ExecResultRecord {
+            result: result.clone(),
+            causes: causes.clone(),
+        })
+    }
+    fn exec_results(&self, exec: &Exec) -> Result<Vec<ExecResult>> {
+        let history: Vec<ExecResultRecord> = try!(Self::read_lines(&self.results_path(exec)));
+        Ok(history.into_iter().rev().map(|r| r.result).collect())
+    }
+    fn clusters(&self) -> Result<Vec<ClusterRecord>> {
+        Self::read_json_dir(&self.root.join("clusters"))
+    }
+    fn add_watchpoint(&self, cluster_id: u64, watchpoint: &WatchPoint) -> Result<()> {
+        Self::append_line(&self.added_watchpoints_path(cluster_id), watchpoint)
+    }
+    fn added_watchpoints(&self, cluster_id: u64) -> Result<Vec<WatchPoint>> {
+        Self::read_lines(&self.added_watchpoints_path(cluster_id))
+    }
+    fn delete_cluster(&self, cluster_id: u64) -> Result<()> {
+        try!(self.delete_histories(cluster_id));
+        for path in vec![self.cluster_path(cluster_id),
+                         self.added_watchpoints_path(cluster_id)] {
+            if path.exists() {
+                try!(fs::remove_file(path));
+            }
+        }
+        Ok(())
+    }
+    fn save_history(&self, history: &History) -> Result<()> {
+        let dir = self.histories_dir(history.cluster_id);
+        try!(fs::create_dir_all(&dir));
+        Self::write_json(&dir.join(format!("{}.json", history.id)), history)
+    }
+    fn histories(&self, cluster_id: u64) -> Result<Vec<History>> {
+        let mut histories: Vec<History> =
+            try!(Self::read_json_dir(&self.histories_dir(cluster_id)));
+        histories.sort_by(|a, b| b.id.cmp(&a.id));
+        Ok(histories)
+    }
+    fn delete_histories(&self, cluster_id: u64) -> Result<()> {
+        let dir = self.histories_dir(cluster_id);
+        if dir.exists() {
+            try!(fs::remove_dir_all(dir));
+        }
+        Ok(())
+    }
+    fn clear(&self) -> Result<()> {
+        for sub in ["clusters", "histories", "perceptions", "results", "watchpoints"].iter() {
+            let dir = self.root.join(sub);
+            if dir.exists() {
+                try!(fs::remove_dir_all(&dir));
+            }
+            try!(fs::create_dir_all(&dir));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use ::flota::config::cluster::watchpoint::WatchPoint;
+    use ::flota::manager::watch::{WatchPointPerception, WatchPointPerceptionValue};
+    use ::flota::store::Store;
+    use super::FileStore;
+
+    #[test]
+    fn test_perception_tail() {
+        let root = env::temp_dir().join(".test_file_store_perception_tail");
+        let _ = fs::remove_dir_all(&root);
+        let store = FileStore::new(&root).expect("failed to create store");
+        let watchpoint = WatchPoint::File { path: PathBuf::from("/tmp/test") };
+        let p1 = WatchPointPerception {
+            value: WatchPointPerceptionValue::File { checksum: vec![1, 2, 3] },
+        };
+        let p2 = WatchPointPerception {
+            value: WatchPointPerceptionValue::File { checksum: vec![4, 5, 6] },
+        };
+        assert!(!store.is_tail_perception(&watchpoint, &p1).unwrap());
+        store.append_perception(&watchpoint, &p1).unwrap();
+        assert!(store.is_tail_perception(&watchpoint, &p1).unwrap());
+        store.append_perception(&watchpoint, &p2).unwrap();
+        assert!(!store.is_tail_perception(&watchpoint, &p1).unwrap());
+        assert!(store.is_tail_perception(&watchpoint, &p2).unwrap());
+        assert_eq!(store.perceptions(&watchpoint).unwrap().len(), 2);
+        fs::remove_dir_all(&root).expect("failed to remove store");
+    }
+
+    #[test]
+    fn test_added_watchpoints() {
+        let root = env::temp_dir().join(".test_file_store_added_watchpoints");
+        let _ = fs::remove_dir_all(&root);
+        let store = FileStore::new(&root).expect("failed to create store");
+        let w1 = WatchPoint::File { path: PathBuf::from("/tmp/a") };
+        let w2 = WatchPoint::File { path: PathBuf::from("/tmp/b") };
+        assert!(store.added_watchpoints(1).unwrap().is_empty());
+        store.add_watchpoint(1, &w1).unwrap();
+        store.add_watchpoint(1, &w2).unwrap();
+        assert_eq!(store.added_watchpoints(1).unwrap(), vec![w1, w2]);
+        store.delete_cluster(1).unwrap();
+        assert!(store.added_watchpoints(1).unwrap().is_empty());
+        fs::remove_dir_all(&root).expect("failed to remove store");
+    }
+}
@@ -0,0 +1,190 @@
+use std::sync::Arc;
+use time;
+use ::exec::ExecResult;
+use ::flota::hash;
+use ::flota::config::Exec;
+use ::flota::config::cluster::Cluster;
+use ::flota::config::cluster::watchpoint::WatchPoint;
+use ::flota::config::setting::{Setting, StoreKind};
+use ::flota::manager::watch::WatchPointPerception;
+use ::flota::test::Cause;
+use ::util::errors::*;
+
+pub mod file;
+pub mod neo4j;
+
+use self::file::FileStore;
+use self::neo4j::Neo4jStore;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WatchPointRecord {
+    pub id: u64,
+    pub watchpoint: WatchPoint,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostRecord {
+    pub id: u64,
+    pub hostname: String,
+    pub template: String,
+}
+
+// Cluster as the API sees it. Its id is derived from the name so that
+// it survives config changes, which config_id does not.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterRecord {
+    pub id: u64,
+    pub name: String,
+    pub config_id: u64,
+    pub watchpoints: Vec<WatchPointRecord>,
+    pub hosts: Vec<HostRecord>,
+}
+
+impl ClusterRecord {
+    pub fn new(cluster: &Cluster) -> Self {
+        ClusterRecord {
+            id: hash(&cluster.name),
+            name: cluster.name.clone(),
+            config_id: cluster.id(),
+            watchpoints: cluster.watchpoints.iter().map(|w| {
+                WatchPointRecord {
+                    id: hash(w),
+                    watchpoint: w.clone(),
+                }
+            }).collect(),
+            hosts: cluster.hosts.iter().map(|h| {
+                HostRecord {
+                    id: hash(&h.hostname),
+                    hostname: h.hostname.clone(),
+                    template: h.template.name.clone(),
+                }
+            }).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RunState {
+    /// Requested but not picked up by the main loop yet.
+    Pending,
+    Running,
+    Finished,
+    /// Given up half way as an exec with abort_on_failure failed.
+    Aborted,
+    Cancelled,
+    /// Given up as something other than an exec failed, e.g. a host
+    /// could not be provisioned.
+    Failed,
+}
+
+// One run of a cluster, from host provisioning to its post tests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct History {
+    /// Milliseconds since epoch when the run started, or was requested.
+    pub id: u64,
+    pub cluster_id: u64,
+    pub config_id: u64,
+    pub causes: Vec<Cause>,
+    pub results: Vec<ExecResult>,
+    pub passed: bool,
+    pub state: RunState,
+    /// Why it has failed, if state is Failed.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+fn now_in_millis() -> u64 {
+    let now = time::get_time();
+    now.sec as u64 * 1000 + now.nsec as u64 / 1000000
+}
+
+impl History {
+    pub fn new(cluster: &Cluster, causes: &Vec<Cause>) -> Self {
+        History {
+            id: now_in_millis(),
+            cluster_id: hash(&cluster.name),
+            config_id: cluster.id(),
+            causes: causes.clone(),
+            results: vec![],
+            passed: true,
+            state: RunState::Running,
+            error: None,
+        }
+    }
+    /// Run requested through the api, which only knows the saved cluster.
+    pub fn requested(cluster: &ClusterRecord, requested_by: &str) -> Self {
+        History {
+            id: now_in_millis(),
+            cluster_id: cluster.id,
+            config_id: cluster.config_id,
+            causes: vec![ Cause::Manual { requested_by: requested_by.to_string() } ],
+            results: vec![],
+            passed: true,
+            state: RunState::Pending,
+            error: None,
+        }
+    }
+    pub fn push(&mut self, result: ExecResult) {
+        self.passed = self.passed && result.passed;
+        self.results.push(result);
+    }
+}
+
+// Everything the manager persists goes through this. Whichever backend
+// is chosen, watchpoint perceptions and exec results are kept as
+// per-parent histories whose newest entry is the tail.
+pub trait Store: Send + Sync {
+    /// Save config graph of the cluster, i.e. its watchpoints, execs,
+    /// and hosts along with their own execs and templates.
+    fn save_cluster(&self, cluster: &Cluster) -> Result<()>;
+    /// True if no exec result has been recorded for the cluster yet.
+    fn is_first_run(&self, cluster: &Cluster) -> Result<bool>;
+    /// Append a perception to the watchpoint's history.
+    fn append_perception(&self,
+                         watchpoint: &WatchPoint,
+                         perception: &WatchPointPerception)
+                         -> Result<()>;
+    /// True if the perception equals the latest one recorded.
+    fn is_tail_perception(&self,
+                          watchpoint: &WatchPoint,
+                          perception: &WatchPointPerception)
+                          -> Result<bool>;
+    /// Perception history of the watchpoint, newest first.
+    fn perceptions(&self, watchpoint: &WatchPoint) -> Result<Vec<WatchPointPerception>>;
+    /// Append an exec result to the exec's history, linked to the causes
+    /// of the run it belongs to.
+    fn append_exec_result(&self,
+                          exec: &Exec,
+                          result: &ExecResult,
+                          causes: &Vec<Cause>)
+                          -> Result<()>;
+    /// Result history of the exec, newest first.
+    fn exec_results(&self, exec: &Exec) -> Result<Vec<ExecResult>>;
+    /// Every cluster saved so far.
+    fn clusters(&self) -> Result<Vec<ClusterRecord>>;
+    /// Watch the watchpoint for the cluster as well as those in the
+    /// config file.
+    fn add_watchpoint(&self, cluster_id: u64, watchpoint: &WatchPoint) -> Result<()>;
+    /// Watchpoints added to the cluster through the api, oldest first.
+    fn added_watchpoints(&self, cluster_id: u64) -> Result<Vec<WatchPoint>>;
+    /// Forget the cluster along with its histories and added watchpoints.
+    fn delete_cluster(&self, cluster_id: u64) -> Result<()>;
+    /// Insert or update the run.
+    fn save_history(&self, history: &History) -> Result<()>;
+    /// Runs of the cluster, newest first.
+    fn histories(&self, cluster_id: u64) -> Result<Vec<History>>;
+    fn delete_histories(&self, cluster_id: u64) -> Result<()>;
+    /// Forget everything ever stored.
+    fn clear(&self) -> Result<()>;
+}
+
+pub fn open(setting: &Setting) -> Result<Arc<Store>> {
+    match setting.store {
+        StoreKind::Neo4j => {
+            Ok(Arc::new(try!(Neo4jStore::new(&try!(setting.database.endpoint())))))
+        },
+        StoreKind::File { ref dir } => {
+            Ok(Arc::new(try!(FileStore::new(dir))))
+        }
+    }
+}
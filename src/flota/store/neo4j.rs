@@ -0,0 +1,319 @@
+use rusted_cypher::Statement;
+use rusted_cypher::graph::GraphClient;
+use rusted_cypher::cypher::transaction::{Started, Transaction};
+use serde_json;
+use ::exec::ExecResult;
+use ::flota::{hash, Cypherable, LABEL_PREFIX};
+use ::flota::config::Exec;
+use ::flota::config::cluster::Cluster;
+use ::flota::config::cluster::host::Host;
+use ::flota::config::cluster::watchpoint::WatchPoint;
+use ::flota::manager::watch::WatchPointPerception;
+use ::flota::test::Cause;
+use ::util::errors::*;
+use super::{ClusterRecord, History, Store};
+
+// Node which results of a scheduled run are due to, as they are to
+// perceptions of watchpoints.
+struct ScheduledRun {
+    at: u64,
+}
+
+impl Cypherable for ScheduledRun {
+    fn cypher_props(&self) -> Vec<(&'static str, String)> {
+        vec![ ("at", self.at.to_string()) ]
+    }
+}
+
+// One client is connected on open and shared by every operation
+// for as long as the store lives.
+pub struct Neo4jStore {
+    graph: GraphClient,
+}
+
+impl Neo4jStore {
+    pub fn new(endpoint: &str) -> Result<Self> {
+        Ok(Neo4jStore {
+            graph: try!(GraphClient::connect(endpoint)),
+        })
+    }
+    fn save_host(transaction: &mut Transaction<Started>, host: &Host) -> Result<()> {
+        // save template
+        try!(save_child_rel!(transaction, host, host.template, "BACKED_BY"));
+
+        // save tests
+        for ref solo_pre_test in host.solo_pre_tests.iter() {
+            try!(save_child_rel!(transaction, host, solo_pre_test, "EXEC"));
+        }
+        for ref solo_test in host.solo_tests.iter() {
+            try!(save_child_rel!(transaction, host, solo_test, "EXEC"));
+        }
+        for ref solo_post_test in host.solo_post_tests.iter() {
+            try!(save_child_rel!(transaction, host, solo_post_test, "EXEC"));
+        }
+        Ok(())
+    }
+    fn save_cluster_inner(transaction: &mut Transaction<Started>, cluster: &Cluster) -> Result<()> {
+        // save watchpoint
+        for ref watchpoint in cluster.watchpoints.iter() {
+            try!(save_child_rel!(transaction, cluster, watchpoint, "WATCH"));
+        }
+
+        // save tests
+        for ref pre_test in cluster.pre_tests.iter() {
+            try!(save_child_rel!(transaction, cluster, pre_test, "EXEC"));
+        }
+        for ref test in cluster.tests.iter() {
+            try!(save_child_rel!(transaction, cluster, test, "EXEC"));
+        }
+        for ref post_test in cluster.post_tests.iter() {
+            try!(save_child_rel!(transaction, cluster, post_test, "EXEC"));
+        }
+
+        // save hosts
+        for ref host in cluster.hosts.iter() {
+            try!(save_child_rel!(transaction, cluster, host, "DEFINE"));
+            try!(Self::save_host(transaction, host));
+        }
+
+        // attach what the api serves. ids are kept as strings since
+        // they may not fit in neo4j's signed integers.
+        let record = ClusterRecord::new(cluster);
+        let mut statement = Statement::new(
+            "MATCH (c: Flota_Cluster { name: {name} })
+             SET c.cluster_id = {cluster_id}, c.record = {record}");
+        try!(statement.add_param("name", &cluster.name));
+        try!(statement.add_param("cluster_id", &record.id.to_string()));
+        try!(statement.add_param("record", &try!(serde_json::to_string(&record))));
+        try!(transaction.exec(statement));
+        Ok(())
+    }
+    fn exec_with_cluster_id(&self, query: &str, cluster_id: u64) -> Result<Vec<String>> {
+        let mut statement = Statement::new(query);
+        try!(statement.add_param("cluster_id", &cluster_id.to_string()));
+        let rows = try!(self.graph.cypher().exec(statement));
+        let mut records = Vec::new();
+        for row in rows.rows() {
+            records.push(try!(row.get("record")));
+        }
+        Ok(records)
+    }
+}
+
+impl Store for Neo4jStore {
+    fn save_cluster(&self, cluster: &Cluster) -> Result<()> {
+        // prepare and start transaction
+        let mut transaction = self.graph.cypher().transaction();
+        transaction.add_statement("MATCH (n: TRANSACTION) RETURN n");
+        let (mut transaction, _) = try!(transaction.begin());
+
+        if let Err(e) = Self::save_cluster_inner(&mut transaction, cluster) {
+            error!("{}", e);
+            try!(transaction.rollback());
+            return Err("failed to save Cluster".into());
+        }
+
+        // commit transaction
+        transaction.commit().map(|_| ()).map_err(|e| e.into())
+    }
+    fn is_first_run(&self, cluster: &Cluster) -> Result<bool> {
+        // XXX: if first and last cluster run was aborted for some reason,
+        // it will mistakenly conclude that it was ok and skip FirstRun until
+        // some watchpoint triggers it. some mechanism to notice cluster
+        // state has to be introduced.
+        self.graph.cypher().exec(cypher_statement!(
+            format!("MATCH (self: {})-[:EXEC]->(t: Flota_Exec)
+                           <-[:IS_RESULT_OF]-(res: Flota_ExecResult)
+                     RETURN res", cluster.cypher_pattern("s")),
+            "s" => cluster
+        )).map(|r| r.rows().count() == 0).map_err(|e| e.into())
+    }
+    fn append_perception(&self,
+                         watchpoint: &WatchPoint,
+                         perception: &WatchPointPerception)
+                         -> Result<()> {
+        // prepare and start transaction
+        let mut transaction = self.graph.cypher().transaction();
+        transaction.add_statement("MATCH (n: TRANSACTION) RETURN n");
+        let (mut transaction, _) = try!(transaction.begin());
+
+        try!(save_child_ll!(&mut transaction, watchpoint, perception, "IS_SNAPSHOT_OF")
+             .map(|_| ()));
+
+        // commit transaction
+        try!(transaction.commit());
+        Ok(())
+    }
+    fn is_tail_perception(&self,
+                          watchpoint: &WatchPoint,
+                          perception: &WatchPointPerception)
+                          -> Result<bool> {
+        is_tail!(self.graph, watchpoint, perception).map_err(|e| e.into())
+    }
+    fn perceptions(&self, watchpoint: &WatchPoint) -> Result<Vec<WatchPointPerception>> {
+        let rows = try!(self.graph.cypher().exec(cypher_statement!(
+            format!("MATCH (w: {})<-[:IS_SNAPSHOT_OF]-(s: Flota_WatchPointPerception)
+                     RETURN s.value AS value ORDER BY id(s) DESC",
+                    watchpoint.cypher_pattern("w")),
+            "w" => watchpoint
+        )));
+        let mut perceptions = Vec::new();
+        for row in rows.rows() {
+            let value: String = try!(row.get("value"));
+            perceptions.push(WatchPointPerception {
+                value: try!(serde_json::from_str(&value)),
+            });
+        }
+        Ok(perceptions)
+    }
+    fn append_exec_result(&self,
+                          exec: &Exec,
+                          result: &ExecResult,
+                          causes: &Vec<Cause>)
+                          -> Result<()> {
+        // prepare and start transaction
+        let mut transaction = self.graph.cypher().transaction();
+        transaction.add_statement("MATCH (n: TRANSACTION) RETURN n");
+        let (mut transaction, _) = try!(transaction.begin());
+
+        try!(save_child_ll!(&mut transaction, exec, result, "IS_RESULT_OF")
+             .map(|_| ()));
+        for cause in causes.iter() {
+            match *cause {
+                Cause::WatchPoint { ref ident, ref changed } => {
+                    try!(save_child_ll!(&mut transaction, ident, result, "DUE_TO")
+                         .map(|_| ()));
+                    // which paths, on the relationship as they are of this
+                    // run rather than of the perception.
+                    let mut statement = cypher_statement!(
+                        format!("MATCH (c: {})-[d:DUE_TO]->(p: {})
+                                 SET d.changed = {{changed}}",
+                                result.cypher_pattern("c"),
+                                ident.cypher_pattern("p")),
+                        "c" => result, "p" => ident
+                    );
+                    try!(statement.add_param("changed", &try!(serde_json::to_string(changed))));
+                    try!(transaction.exec(statement));
+                },
+                Cause::Scheduled { at } => {
+                    let scheduled = ScheduledRun { at: at };
+                    try!(save_child_ll!(&mut transaction, scheduled, result, "DUE_TO")
+                         .map(|_| ()));
+                },
+                _ => {},
+            }
+        }
+
+        // commit transaction
+        try!(transaction.commit());
+        Ok(())
+    }
+    fn exec_results(&self, exec: &Exec) -> Result<Vec<ExecResult>> {
+        let rows = try!(self.graph.cypher().exec(cypher_statement!(
+            format!("MATCH (e: {})<-[:IS_RESULT_OF]-(r: Flota_ExecResult)
+                     RETURN r.host AS host, r.command AS command,
+                            r.expected AS expected, r.result AS result,
+                            r.passed AS passed,
+                            coalesce(r.failure, 'null') AS failure,
+                            coalesce(r.skipped, 'false') AS skipped
+                     ORDER BY id(r) DESC",
+                    exec.cypher_pattern("e")),
+            "e" => exec
+        )));
+        let mut results = Vec::new();
+        for row in rows.rows() {
+            let expected: String = try!(row.get("expected"));
+            let result: String = try!(row.get("result"));
+            let passed: String = try!(row.get("passed"));
+            let skipped: String = try!(row.get("skipped"));
+            let failure: String = try!(row.get("failure"));
+            results.push(ExecResult {
+                host: try!(row.get("host")),
+                command: try!(row.get("command")),
+                expected: try!(serde_json::from_str(&expected)),
+                result: try!(serde_json::from_str(&result)),
+                passed: passed == "true",
+                failure: try!(serde_json::from_str(&failure)),
+                skipped: skipped == "true",
+            });
+        }
+        Ok(results)
+    }
+    fn clusters(&self) -> Result<Vec<ClusterRecord>> {
+        let rows = try!(self.graph.cypher().exec(
+            "MATCH (c: Flota_Cluster) WHERE exists(c.record) RETURN c.record AS record"));
+        let mut clusters = Vec::new();
+        for row in rows.rows() {
+            let record: String = try!(row.get("record"));
+            clusters.push(try!(serde_json::from_str(&record)));
+        }
+        Ok(clusters)
+    }
+    fn add_watchpoint(&self, cluster_id: u64, watchpoint: &WatchPoint) -> Result<()> {
+        let mut statement = Statement::new(
+            "MATCH (c: Flota_Cluster { cluster_id: {cluster_id} })
+             MERGE (w: Flota_AddedWatchPoint { watchpoint_id: {watchpoint_id} })-[:ADDED_TO]->(c)
+             ON CREATE SET w.record = {record}, w.added_at = timestamp()");
+        try!(statement.add_param("cluster_id", &cluster_id.to_string()));
+        try!(statement.add_param("watchpoint_id", &hash(watchpoint).to_string()));
+        try!(statement.add_param("record", &try!(serde_json::to_string(watchpoint))));
+        try!(self.graph.cypher().exec(statement));
+        Ok(())
+    }
+    fn added_watchpoints(&self, cluster_id: u64) -> Result<Vec<WatchPoint>> {
+        let mut watchpoints = Vec::new();
+        for record in try!(self.exec_with_cluster_id(
+            "MATCH (w: Flota_AddedWatchPoint)
+                   -[:ADDED_TO]->(c: Flota_Cluster { cluster_id: {cluster_id} })
+             RETURN w.record AS record ORDER BY w.added_at", cluster_id)) {
+            watchpoints.push(try!(serde_json::from_str::<WatchPoint>(&record)));
+        }
+        Ok(watchpoints)
+    }
+    fn delete_cluster(&self, cluster_id: u64) -> Result<()> {
+        try!(self.exec_with_cluster_id(
+            "MATCH (c: Flota_Cluster { cluster_id: {cluster_id} })
+             OPTIONAL MATCH (h: Flota_History)-[:HISTORY_OF]->(c)
+             OPTIONAL MATCH (w: Flota_AddedWatchPoint)-[:ADDED_TO]->(c)
+             DETACH DELETE h, w, c", cluster_id));
+        Ok(())
+    }
+    fn save_history(&self, history: &History) -> Result<()> {
+        let mut statement = Statement::new(
+            "MATCH (c: Flota_Cluster { cluster_id: {cluster_id} })
+             MERGE (h: Flota_History { history_id: {history_id} })-[:HISTORY_OF]->(c)
+             SET h.record = {record}");
+        try!(statement.add_param("cluster_id", &history.cluster_id.to_string()));
+        try!(statement.add_param("history_id", &history.id.to_string()));
+        try!(statement.add_param("record", &try!(serde_json::to_string(history))));
+        try!(self.graph.cypher().exec(statement));
+        Ok(())
+    }
+    fn histories(&self, cluster_id: u64) -> Result<Vec<History>> {
+        let mut histories = Vec::new();
+        for record in try!(self.exec_with_cluster_id(
+            "MATCH (h: Flota_History)-[:HISTORY_OF]->(c: Flota_Cluster { cluster_id: {cluster_id} })
+             RETURN h.record AS record", cluster_id)) {
+            histories.push(try!(serde_json::from_str::<History>(&record)));
+        }
+        histories.sort_by(|a, b| b.id.cmp(&a.id));
+        Ok(histories)
+    }
+    fn delete_histories(&self, cluster_id: u64) -> Result<()> {
+        try!(self.exec_with_cluster_id(
+            "MATCH (h: Flota_History)-[:HISTORY_OF]->(c: Flota_Cluster { cluster_id: {cluster_id} })
+             DETACH DELETE h", cluster_id));
+        Ok(())
+    }
+    fn clear(&self) -> Result<()> {
+        // only the labels of our own, the database may well be shared.
+        for label in ["Cluster", "Host", "Template", "Exec", "WatchPoint",
+                      "WatchPointPerception", "ExecResult", "History",
+                      "AddedWatchPoint", "ScheduledRun"].iter() {
+            try!(self.graph.cypher().exec(
+                Statement::new(&format!("MATCH (n: {}{}) DETACH DELETE n",
+                                        LABEL_PREFIX, label))));
+        }
+        Ok(())
+    }
+}
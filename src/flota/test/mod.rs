@@ -1,10 +1,20 @@
 use ::flota::manager::watch::WatchPointPerception;
 
 // this indicated a cause to run tests
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Cause {
     FirstRun,
     WatchPoint {
         ident: WatchPointPerception,
-    }
+        /// Paths which have changed, for watchpoints which know them.
+        #[serde(default)]
+        changed: Vec<String>,
+    },
+    Scheduled {
+        /// Milliseconds since epoch of the minute it was scheduled at.
+        at: u64,
+    },
+    Manual {
+        requested_by: String,
+    },
 }
@@ -1,5 +1,6 @@
 use rusted_cypher::error as cypher;
 use notify;
+use serde_json;
 use ssh2;
 use std::io;
 use std::string;
@@ -12,6 +13,7 @@ error_chain! {
         io::Error, IO;
         mpsc::RecvError, MpscRecv;
         notify::Error, Notify;
+        serde_json::Error, Json;
         ssh2::Error, SSH2;
     }
 }
@@ -1,8 +1,11 @@
+use libc;
+use std::ffi::CStr;
 use std::ptr;
 use std::slice;
 use ::libvirt::*;
 use ::util::errors::*;
 use ::virt::domain::Domain;
+use ::virt::network::Network;
 
 #[derive(Debug, Clone)]
 pub struct Conn {
@@ -19,18 +22,35 @@ impl Drop for Conn {
 
 impl Conn {
     pub fn new(uri: &str) -> Self {
+        match Self::open(uri) {
+            Ok(conn) => conn,
+            Err(e) => panic!("{}", e),
+        }
+    }
+    pub fn open(uri: &str) -> Result<Self> {
         unsafe {
             let raw = virConnectOpen(rawCharPtr!(uri));
             if raw.is_null() {
-                panic!("failed to open connection to {}", uri);
+                return Err(format!("failed to open connection to {}", uri).into());
             }
             virConnSetErrorFunc(raw, ptr::null_mut(), Some(defaultVirtErrorFunc));
-            Conn { raw: raw }
+            Ok(Conn { raw: raw })
         }
     }
     pub fn raw(&self) -> virConnectPtr {
         self.raw
     }
+    pub fn uri(&self) -> Result<String> {
+        unsafe {
+            let ptr = virConnectGetURI(self.raw());
+            if ptr.is_null() {
+                return Err("failed to get uri of connection".into());
+            }
+            let uri = CStr::from_ptr(ptr).to_string_lossy().into_owned();
+            libc::free(ptr as *mut libc::c_void);
+            Ok(uri)
+        }
+    }
     pub fn domains(&self, flags: u32) -> Result<Vec<Domain>> {
         let mut domains: *mut virDomainPtr = ptr::null_mut();
         match unsafe { virConnectListAllDomains(self.raw(), &mut domains, flags) } {
@@ -46,6 +66,21 @@ impl Conn {
             }
         }
     }
+    pub fn networks(&self) -> Result<Vec<Network>> {
+        let mut networks: *mut virNetworkPtr = ptr::null_mut();
+        match unsafe { virConnectListAllNetworks(self.raw(), &mut networks, 0) } {
+            -1 => Err("failed to list networks".into()),
+            n => {
+                Ok(unsafe {
+                    slice::from_raw_parts(networks, n as usize)
+                        .iter()
+                        .filter(|n| !n.is_null())
+                        .map(|n| Network { raw: &mut **n })
+                        .collect::<Vec<Network>>()
+                })
+            }
+        }
+    }
 }
 
 #[allow(non_snake_case)]
@@ -0,0 +1,33 @@
+use libc;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_uint};
+use ::libvirt::*;
+use ::util::errors::*;
+use ::virt::domain::Domain;
+
+// libvirt-qemu is not part of the generated bindings.
+#[link(name = "virt-qemu")]
+extern "C" {
+    fn virDomainQemuAgentCommand(domain: virDomainPtr,
+                                 cmd: *const c_char,
+                                 timeout: c_int,
+                                 flags: c_uint)
+                                 -> *mut c_char;
+}
+
+impl Domain {
+    /// Send the command to qemu-guest-agent running inside the domain and
+    /// return its response, both in JSON.
+    pub fn agent_command(&self, cmd: &str, timeout_secs: i32) -> Result<String> {
+        let cmd = CString::new(cmd).unwrap();
+        unsafe {
+            let ptr = virDomainQemuAgentCommand(self.raw(), cmd.as_ptr(), timeout_secs, 0);
+            if ptr.is_null() {
+                return Err(format!("guest agent of {} did not respond", self.name()).into());
+            }
+            let resp = CStr::from_ptr(ptr).to_string_lossy().into_owned();
+            libc::free(ptr as *mut libc::c_void);
+            Ok(resp)
+        }
+    }
+}
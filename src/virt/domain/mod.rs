@@ -1,19 +1,53 @@
 extern crate xml;
 use std::ffi::{CStr, CString};
 use std::path::{Path, PathBuf};
+use std::ptr;
 use xml::{Event, Parser};
 use ::libvirt::*;
 use ::util::errors::*;
 use ::util::ipv4::IPv4;
 use ::virt::conn::Conn;
 use ::virt::storage::volume::Volume;
+use ::virt::Role;
 use ::virt::network::Network;
+use ::virt::stream::Stream;
 
+pub mod agent;
 pub mod snapshot;
 
 resource!(Domain, virDomain);
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DomainState {
+    NoState,
+    Running,
+    Blocked,
+    Paused,
+    Shutdown,
+    Shutoff,
+    Crashed,
+    PMSuspended,
+}
+
 impl Domain {
+    pub fn state(&self) -> Result<DomainState> {
+        let mut state = -1 as i32;
+        let mut reason = -1 as i32;
+        if unsafe { virDomainGetState(self.raw(), &mut state, &mut reason, 0) } < 0 {
+            return Err(format!("failed to get state of domain: {}", self.name()).into());
+        }
+        match state {
+            s if s == virDomainState::VIR_DOMAIN_NOSTATE as i32 => Ok(DomainState::NoState),
+            s if s == virDomainState::VIR_DOMAIN_RUNNING as i32 => Ok(DomainState::Running),
+            s if s == virDomainState::VIR_DOMAIN_BLOCKED as i32 => Ok(DomainState::Blocked),
+            s if s == virDomainState::VIR_DOMAIN_PAUSED as i32 => Ok(DomainState::Paused),
+            s if s == virDomainState::VIR_DOMAIN_SHUTDOWN as i32 => Ok(DomainState::Shutdown),
+            s if s == virDomainState::VIR_DOMAIN_SHUTOFF as i32 => Ok(DomainState::Shutoff),
+            s if s == virDomainState::VIR_DOMAIN_CRASHED as i32 => Ok(DomainState::Crashed),
+            s if s == virDomainState::VIR_DOMAIN_PMSUSPENDED as i32 => Ok(DomainState::PMSuspended),
+            s => Err(format!("unknown state of domain {}: {}", self.name(), s).into()),
+        }
+    }
     pub fn volume_paths(&self) -> Vec<PathBuf> {
         let desc = self.xml().unwrap();
         let mut p = Parser::new();
@@ -41,8 +75,33 @@ impl Domain {
         }
         sources
     }
-    pub fn networks(&self) -> Vec<&Network> {
-        vec![]
+    pub fn networks(&self, conn: &Conn) -> Vec<Network> {
+        let desc = self.xml().unwrap();
+        let mut p = Parser::new();
+        p.feed_str(&desc);
+        let mut in_tag = false;
+        let mut networks = Vec::new();
+        for event in p {
+            match event.unwrap() {
+                Event::ElementStart(ref tag) if tag.name == "interface".to_string() => {
+                    in_tag = tag.attributes.get(&("type".to_string(), None))
+                                .map(|t| t == "network")
+                                .unwrap_or(false);
+                }
+                Event::ElementStart(ref tag) if in_tag && tag.name == "source".to_string() => {
+                    if let Some(name) = tag.attributes.get(&("network".to_string(), None)) {
+                        if let Some(network) = Network::find(name, conn) {
+                            networks.push(network);
+                        }
+                    }
+                }
+                Event::ElementEnd(ref tag) if in_tag && tag.name == "interface".to_string() => {
+                    in_tag = false;
+                }
+                _ => (),
+            }
+        }
+        networks
     }
     // TODO: secondary ip
     pub fn ip_in_network(&self, network: &Network) -> Result<IPv4> {
@@ -135,6 +194,20 @@ impl Domain {
         }
         mac
     }
+    /// Connect the stream to the serial console, taking it over from
+    /// whoever has it open.
+    pub fn open_console(&self, stream: &Stream) -> Result<()> {
+        let flags = virDomainConsoleFlags::VIR_DOMAIN_CONSOLE_FORCE as u32;
+        if unsafe { virDomainOpenConsole(self.raw(), ptr::null(), stream.raw(), flags) } < 0 {
+            Err(format!("failed to open console of {}", self.name()).into())
+        } else {
+            Ok(())
+        }
+    }
+    /// File the serial console of the domain is logged to.
+    pub fn serial_log_path(name: &str) -> PathBuf {
+        Path::new("/var/lib/libvirt/qemu").join(format!("{}-serial0.log", name))
+    }
     pub fn find(name: &str, conn: &Conn) -> Option<Domain> {
         match unsafe {
             virDomainLookupByName(conn.raw(), CString::new(name.to_owned()).unwrap().as_ptr())
@@ -171,6 +244,7 @@ impl Domain {
                     let mut x = xE!("domain", type => "kvm");
                     x.tag(xE!("name"))
                         .text(hostname.to_owned().into());
+                    x.tag(Role::Host.metadata());
                     x.tag(xE!("memory", unit => "KiB"))
                         .text((mem_mb * 1024).to_string().into());
                     x.tag(xE!("vcpu", placement => "static"))
@@ -223,9 +297,16 @@ impl Domain {
                             .tag_stay(xE!("start", mode => "onboot"))
                             .tag_stay(xE!("source", network => default_network.unwrap().name()));
                     }
-                    // console
+                    // console, logged to a file as well (libvirt >= 1.3.3)
+                    let log_file_serial0 = Domain::serial_log_path(hostname);
                     x_dev.tag(xE!("console", type => "pty"))
-                        .tag_stay(xE!("target", type => "serial", port => "0"));
+                        .tag_stay(xE!("target", type => "serial", port => "0"))
+                        .tag_stay(xE!("log", file => log_file_serial0.to_str().unwrap(),
+                                             append => "off"));
+                    // for qemu-guest-agent
+                    x_dev.tag(xE!("channel", type => "unix"))
+                        .tag_stay(xE!("target", type => "virtio",
+                                                name => "org.qemu.guest_agent.0"));
 
                     x.tag(x_dev);
                     virDomainDefineXML(conn.raw(), CString::new(format!("{}", x)).unwrap().as_ptr())
@@ -265,6 +346,15 @@ impl Domain {
             Ok(())
         }
     }
+    // snapshot metadata would otherwise prevent it from being undefined.
+    pub fn undefine_with_snapshots(&self) -> Result<()> {
+        let flags = virDomainUndefineFlagsValues::VIR_DOMAIN_UNDEFINE_SNAPSHOTS_METADATA as u32;
+        if unsafe { virDomainUndefineFlags(self.raw(), flags) } < 0 {
+            Err("failed to undefine".into())
+        } else {
+            Ok(())
+        }
+    }
     pub fn delete(&self) -> Result<()> {
         try!(self.destroy());
         self.undefine()
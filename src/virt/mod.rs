@@ -1,4 +1,7 @@
 extern crate xml;
+use std::collections::HashSet;
+use xml::{Event, Parser};
+use ::consts::*;
 use ::util::errors::*;
 
 #[macro_export]
@@ -39,6 +42,9 @@ impl $name {
             }
         }
     }
+    pub fn role(&self) -> Option<::virt::Role> {
+        self.xml().and_then(|desc| ::virt::Role::of_xml(&desc))
+    }
 }
 }}
 
@@ -46,6 +52,7 @@ pub mod conn;
 pub mod domain;
 pub mod network;
 pub mod storage;
+pub mod stream;
 
 use self::conn::Conn;
 use self::domain::Domain;
@@ -100,29 +107,144 @@ impl<'a> ResourceBlend<'a> {
     }
 }
 
-pub fn clean_associated(rb: &ResourceBlend) -> Result<()> {
-    let domains = try!(rb.conn().domains(0));
-    for domain in domains.iter() {
-        let vol_paths = domain.volume_paths();
-        let networks = domain.networks();
-        try!(domain.delete());
-        for network in networks {
-            match network.name() {
-                "default" => (),
-                _ => {
-                    // Intentionally skip error becuase it will be
-                    // deleted after all, at the last moment all domains
-                    // utilising it were deleted. So as dummy nic
-                    // associated with its virtual bridge.
-                    let _ = network.delete();
+// Everything we define is tagged in its <metadata> so that we never lay
+// hands on the others' on a shared hypervisor. Volumes cannot be tagged,
+// but they only live in our own pool or are named after our domains.
+pub const METADATA_NS: &'static str = "https://github.com/lkpdn/flota";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Host of a cluster.
+    Host,
+    /// Image a template is built into.
+    Template,
+    Network,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Role::Host => "host",
+            Role::Template => "template",
+            Role::Network => "network",
+        }
+    }
+    /// <metadata> element to be put in the xml of the object.
+    pub fn metadata(&self) -> xml::Element {
+        let mut x = xE!("metadata");
+        x.tag(xml::Element::new("flota:resource".into(),
+                                None,
+                                vec![("xmlns:flota".into(), None, METADATA_NS.into()),
+                                     ("role".into(), None, self.as_str().into())]));
+        x
+    }
+    pub fn of_xml(desc: &str) -> Option<Role> {
+        let mut p = Parser::new();
+        p.feed_str(desc);
+        for event in p {
+            if let Ok(Event::ElementStart(tag)) = event {
+                if tag.name != "resource" || tag.ns.as_ref().map(|ns| ns.as_str()) != Some(METADATA_NS) {
+                    continue;
                 }
+                return match tag.attributes.get(&("role".to_string(), None)).map(|r| r.as_str()) {
+                    Some("host") => Some(Role::Host),
+                    Some("template") => Some(Role::Template),
+                    Some("network") => Some(Role::Network),
+                    _ => None,
+                };
             }
         }
-        for vol_path in vol_paths.iter() {
-            if let Some(volume) = Volume::from_path(rb.conn(), &vol_path) {
-                let _ = volume.delete();
+        None
+    }
+}
+
+/// Remove the domain along with its snapshots and volumes, including
+/// those left over in the pool from an image build.
+pub fn remove_domain(conn: &Conn, pool: Option<&StoragePool>, domain: &Domain) -> Result<()> {
+    let name = domain.name().to_owned();
+    let vol_paths = domain.volume_paths();
+    try!(domain.destroy());
+    try!(domain.undefine_with_snapshots());
+    for vol_path in vol_paths.iter() {
+        if let Some(volume) = Volume::from_path(conn, vol_path) {
+            try!(volume.delete());
+        }
+    }
+    if let Some(pool) = pool {
+        for suffix in ["000.qed", "001.qcow2", "001.RAM"].iter() {
+            if let Some(volume) = Volume::find(&format!("{}.{}", name, suffix), pool) {
+                try!(volume.delete());
             }
         }
     }
     Ok(())
 }
+
+/// Remove hosts and the networks only they were using. On reset, also
+/// template images, all our networks and the pool if it is ours by its
+/// default name. Returns what has been removed.
+pub fn clean(conn: &Conn, pool_name: &str, reset: bool) -> Result<Vec<String>> {
+    let pool = StoragePool::find(conn, pool_name);
+    let mut removed = Vec::new();
+    let mut released = HashSet::new();
+    let mut in_use = HashSet::new();
+    for domain in try!(conn.domains(0)).iter() {
+        let networks = domain.networks(conn)
+                             .iter()
+                             .map(|n| n.name().to_owned())
+                             .collect::<Vec<_>>();
+        match domain.role() {
+            Some(Role::Host) => (),
+            Some(Role::Template) if reset => (),
+            _ => {
+                in_use.extend(networks);
+                continue;
+            }
+        }
+        try!(remove_domain(conn, pool.as_ref(), domain));
+        removed.push(format!("domain {}", domain.name()));
+        released.extend(networks);
+    }
+    for network in try!(conn.networks()).iter() {
+        let name = network.name().to_owned();
+        if network.role() != Some(Role::Network) || in_use.contains(&name) {
+            continue;
+        }
+        if reset || released.contains(&name) {
+            try!(network.delete());
+            removed.push(format!("network {}", name));
+        }
+    }
+    if reset {
+        if let Some(pool) = pool {
+            // one named otherwise may well be shared, e.g. libvirt's default.
+            if pool_name != format!("_{}", *PROGNAME) {
+                warn!("pool {} is left as it is not created by us", pool_name);
+            } else if try!(pool.volumes()).is_empty() {
+                try!(pool.delete());
+                removed.push(format!("pool {}", pool_name));
+            } else {
+                warn!("pool {} is left as it still has volumes not of ours", pool_name);
+            }
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use xml;
+    use super::Role;
+
+    #[test]
+    fn test_role_of_xml() {
+        let mut x = xE!("domain", type => "kvm");
+        x.tag(xE!("name")).text("h1".into());
+        x.tag(Role::Host.metadata());
+        assert_eq!(Role::of_xml(&format!("{}", x)), Some(Role::Host));
+        // same element name in another namespace is not ours.
+        let desc = "<domain><metadata><x:resource xmlns:x=\"http://example.com\" role=\"host\"/>\
+                    </metadata></domain>";
+        assert_eq!(Role::of_xml(desc), None);
+    }
+}
@@ -6,11 +6,18 @@ use std::slice;
 use ::libvirt::*;
 use ::util::errors::*;
 use ::util::ipv4::IPv4;
+use ::virt::Role;
 use ::virt::conn::Conn;
 
 resource!(Network, virNetwork);
 
 impl Network {
+    pub fn find(name: &str, conn: &Conn) -> Option<Network> {
+        match unsafe { virNetworkLookupByName(conn.raw(), rawCharPtr!(name)) } {
+            p if !p.is_null() => Some(Network { raw: p }),
+            _ => None,
+        }
+    }
     /// get DHCP leases.
     fn leases(&self) -> Result<Vec<virNetworkDHCPLease>> {
         let mut leases: *mut virNetworkDHCPLeasePtr = ptr::null_mut();
@@ -95,6 +102,7 @@ impl Network {
                     let mut x_nw = xE!("network");
                     x_nw.tag(xE!("name"))
                         .text(nw_name.into());
+                    x_nw.tag(Role::Network.metadata());
                     x_nw.tag(xE!("bridge", name => br_name));
                     x_nw.tag(xE!("forward", mode => "nat"));
                     let mut x_nw_ip = xE!("ip",
@@ -112,9 +112,15 @@ impl StoragePool {
                 .content_str())
         }
     }
-    #[allow(dead_code)]
+    pub fn find(conn: &Conn, name: &str) -> Option<StoragePool> {
+        match unsafe { virStoragePoolLookupByName(conn.raw(), rawCharPtr!(name)) } {
+            p if !p.is_null() => Some(StoragePool { raw: p }),
+            _ => None,
+        }
+    }
     fn destroy(&self) -> Result<()> {
-        if unsafe { virStoragePoolDestroy(self.raw) } < 0 {
+        if unsafe { virStoragePoolIsActive(self.raw) } == 1 &&
+           unsafe { virStoragePoolDestroy(self.raw) } < 0 {
             Err(format!(
               "cannot destroy storage pool: {}", self.name()
             )
@@ -123,4 +129,15 @@ impl StoragePool {
             Ok(())
         }
     }
+    pub fn delete(&self) -> Result<()> {
+        try!(self.destroy());
+        if unsafe { virStoragePoolUndefine(self.raw) } < 0 {
+            Err(format!(
+              "cannot undefine storage pool: {}", self.name()
+            )
+                .into())
+        } else {
+            Ok(())
+        }
+    }
 }
@@ -0,0 +1,49 @@
+use ::libvirt::*;
+use ::util::errors::*;
+use ::virt::conn::Conn;
+
+// Non-blocking byte stream, e.g. to a domain console.
+pub struct Stream {
+    raw: virStreamPtr,
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        unsafe {
+            // the other end never finishes by itself, thus abort.
+            virStreamAbort(self.raw);
+            if virStreamFree(self.raw) < 0 {
+                error!("failed to drop raw ptr in Stream");
+            }
+        }
+    }
+}
+
+impl Stream {
+    pub fn new(conn: &Conn) -> Result<Self> {
+        let flags = virStreamFlags::VIR_STREAM_NONBLOCK as u32;
+        match unsafe { virStreamNew(conn.raw(), flags) } {
+            p if !p.is_null() => Ok(Stream { raw: p }),
+            _ => Err("failed to create stream".into()),
+        }
+    }
+    pub unsafe fn raw(&self) -> virStreamPtr {
+        self.raw
+    }
+    /// Bytes sent so far, which may be fewer than given.
+    pub fn send(&self, data: &[u8]) -> Result<usize> {
+        match unsafe { virStreamSend(self.raw, data.as_ptr() as *const _, data.len() as _) } {
+            -2 => Ok(0),
+            n if n < 0 => Err("failed to send to stream".into()),
+            n => Ok(n as usize),
+        }
+    }
+    /// None if nothing has arrived yet, Some(0) on the end of stream.
+    pub fn recv(&self, buf: &mut [u8]) -> Result<Option<usize>> {
+        match unsafe { virStreamRecv(self.raw, buf.as_mut_ptr() as *mut _, buf.len() as _) } {
+            -2 => Ok(None),
+            n if n < 0 => Err("failed to receive from stream".into()),
+            n => Ok(Some(n as usize)),
+        }
+    }
+}